@@ -0,0 +1,172 @@
+// auth.rs
+use std::env;
+use std::future::{Future, ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use redis::Client;
+use uuid::Uuid;
+
+use crate::errors::ApiError;
+use crate::jwt::{Claims, JwtKeys, TokenError};
+use crate::tokens;
+
+// User of the request, decoded once from the token and cached in the request extensions
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: Uuid,
+    pub role: String,
+    pub claims: Claims,
+}
+
+impl AuthenticatedUser {
+    // Server and admin tokens can act on behalf of other users
+    pub fn is_privileged(&self) -> bool {
+        self.role == "server" || self.role == "admin"
+    }
+}
+
+//Decode the token of the request and check it has not been revoked
+pub async fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, ApiError> {
+    if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+        return Ok(user.clone());
+    }
+
+    let token = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .ok_or(ApiError::MissingToken)?
+        .to_str()
+        .map_err(|_| ApiError::InvalidToken)?;
+    let (keys, redis) = match (req.app_data::<Data<JwtKeys>>(), req.app_data::<Data<Client>>()) {
+        (Some(keys), Some(redis)) => (keys, redis),
+        _ => return Err(ApiError::Internal("JWT keys or Redis client not registered".to_string())),
+    };
+
+    let claims = keys.verify(token).map_err(|e| match e {
+        TokenError::RetiredKey(_) => ApiError::RetiredKey,
+        _ => ApiError::InvalidToken,
+    })?;
+    let id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::InvalidToken)?;
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    if tokens::is_revoked(&mut con, &claims).await? {
+        return Err(ApiError::TokenRevoked);
+    }
+
+    let user = AuthenticatedUser {
+        id,
+        role: claims.role.clone(),
+        claims,
+    };
+    req.extensions_mut().insert(user.clone());
+    Ok(user)
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+// Shared secret allowing deployment tooling to create server accounts without an admin token
+//
+// Read from SERVER_PROVISIONING_TOKEN, provisioning is disabled when it is not set
+pub struct ProvisioningToken {
+    token: Option<String>,
+}
+
+impl ProvisioningToken {
+    pub const HEADER: &'static str = "X-Provisioning-Token";
+
+    pub fn from_env() -> ProvisioningToken {
+        ProvisioningToken {
+            token: env::var("SERVER_PROVISIONING_TOKEN").ok().filter(|token| !token.is_empty()),
+        }
+    }
+
+    pub fn check(&self, req: &HttpRequest) -> Result<(), ApiError> {
+        let expected = self.token.as_ref().ok_or(ApiError::InvalidProvisioningToken)?;
+        let given = req
+            .headers()
+            .get(Self::HEADER)
+            .ok_or(ApiError::InvalidProvisioningToken)?
+            .as_bytes();
+
+        // Compare every byte so the time taken doesn't leak how much of the token matched
+        let matches = given.len() == expected.len()
+            && given.iter().zip(expected.as_bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+        if matches {
+            Ok(())
+        } else {
+            Err(ApiError::InvalidProvisioningToken)
+        }
+    }
+}
+
+// Middleware restricting a route or a scope to the given roles
+pub struct RequireRole {
+    roles: &'static [&'static str],
+}
+
+impl RequireRole {
+    pub fn new(roles: &'static [&'static str]) -> RequireRole {
+        RequireRole { roles }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service: Rc::new(service),
+            roles: self.roles,
+        }))
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: Rc<S>,
+    roles: &'static [&'static str],
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let roles = self.roles;
+
+        Box::pin(async move {
+            let user = authenticate(req.request()).await?;
+            if !roles.contains(&user.role.as_str()) {
+                return Err(ApiError::Forbidden.into());
+            }
+            service.call(req).await
+        })
+    }
+}
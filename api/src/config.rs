@@ -0,0 +1,26 @@
+// config.rs
+use std::{env, io};
+use std::str::FromStr;
+
+// Read an optional setting from the environment, falling back to its default value
+pub fn env_or<T: FromStr>(name: &str, default: T) -> io::Result<T> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::other(format!("Invalid value for {} : {}", name, value))),
+        _ => Ok(default),
+    }
+}
+
+// Read an optional `,` separated list from the environment
+pub fn env_list_or(name: &str, default: &[&str]) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
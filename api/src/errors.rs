@@ -0,0 +1,312 @@
+// errors.rs
+use std::fmt;
+
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::{header, StatusCode};
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use redis::RedisError;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Resource {
+    User,
+    Friend,
+    Role,
+    Rank,
+    Session,
+    Achievement,
+    GameMode,
+    Match,
+    FriendRequest,
+    PartyInvite,
+}
+
+// Every error returned by the API, rendered as a JSON body with a stable code
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    InvalidRole,
+    InvalidCredentials,
+    InvalidRefreshToken,
+    InvalidProvisioningToken,
+    InvalidAccountToken,
+    MissingToken,
+    InvalidToken,
+    RetiredKey,
+    TokenRevoked,
+    Forbidden,
+    ActingForOtherUser,
+    NotFriends,
+    SessionPrivate,
+    EmailNotVerified,
+    NotFound(Resource),
+    NotQueued,
+    AlreadyQueued,
+    AlreadyInSession,
+    SessionFull,
+    NotInSession,
+    FriendNotInSession,
+    SessionNotJoinable,
+    SessionClosed,
+    MatchInProgress,
+    // Current and requested state of the session
+    InvalidStateTransition(&'static str, &'static str),
+    // Players the game mode of the session needs at least
+    NotEnoughPlayers(i32),
+    Validation(Vec<FieldError>),
+    // Unique constraint violation, with the conflicting field when it is known
+    Conflict(Option<String>),
+    // Seconds before the client can try again
+    TooManyRequests(u64),
+    DatabaseUnavailable,
+    RedisUnavailable,
+    Internal(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &'static str, message: String) -> FieldError {
+        FieldError {
+            field: field.to_string(),
+            code,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+impl Resource {
+    fn name(&self) -> &'static str {
+        match self {
+            Resource::User => "User",
+            Resource::Friend => "Friend",
+            Resource::Role => "Role",
+            Resource::Rank => "Rank",
+            Resource::Session => "Session",
+            Resource::Achievement => "Achievement",
+            Resource::GameMode => "Game mode",
+            Resource::Match => "Match",
+            Resource::FriendRequest => "Friend request",
+            Resource::PartyInvite => "Party invite",
+        }
+    }
+
+    fn not_found_code(&self) -> &'static str {
+        match self {
+            Resource::User => "user_not_found",
+            Resource::Friend => "friend_not_found",
+            Resource::Role => "role_not_found",
+            Resource::Rank => "rank_not_found",
+            Resource::Session => "session_not_found",
+            Resource::Achievement => "achievement_not_found",
+            Resource::GameMode => "game_mode_not_found",
+            Resource::Match => "match_not_found",
+            Resource::FriendRequest => "friend_request_not_found",
+            Resource::PartyInvite => "party_invite_not_found",
+        }
+    }
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::InvalidRole => "invalid_role",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::InvalidRefreshToken => "invalid_refresh_token",
+            ApiError::InvalidProvisioningToken => "invalid_provisioning_token",
+            ApiError::InvalidAccountToken => "invalid_account_token",
+            ApiError::MissingToken => "missing_token",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::RetiredKey => "retired_key",
+            ApiError::TokenRevoked => "token_revoked",
+            ApiError::Forbidden => "forbidden",
+            ApiError::ActingForOtherUser => "acting_for_other_user",
+            ApiError::EmailNotVerified => "email_not_verified",
+            ApiError::NotFound(resource) => resource.not_found_code(),
+            ApiError::NotQueued => "not_queued",
+            ApiError::AlreadyQueued => "already_queued",
+            ApiError::AlreadyInSession => "already_in_session",
+            ApiError::SessionFull => "session_full",
+            ApiError::NotInSession => "not_in_session",
+            ApiError::FriendNotInSession => "friend_not_in_session",
+            ApiError::NotFriends => "not_friends",
+            ApiError::SessionPrivate => "session_private",
+            ApiError::SessionNotJoinable => "session_not_joinable",
+            ApiError::SessionClosed => "session_closed",
+            ApiError::MatchInProgress => "match_in_progress",
+            ApiError::InvalidStateTransition(_, _) => "invalid_state_transition",
+            ApiError::NotEnoughPlayers(_) => "not_enough_players",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::DatabaseUnavailable => "database_unavailable",
+            ApiError::RedisUnavailable => "redis_unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message) => write!(f, "{}", message),
+            ApiError::InvalidRole => write!(f, "Invalid role name provided"),
+            ApiError::InvalidCredentials => write!(f, "Invalid username or password"),
+            ApiError::InvalidRefreshToken => write!(f, "Invalid refresh token"),
+            ApiError::InvalidProvisioningToken => write!(f, "Invalid provisioning token"),
+            ApiError::InvalidAccountToken => write!(f, "Invalid or expired token"),
+            ApiError::MissingToken => write!(f, "Unauthorized"),
+            ApiError::InvalidToken => write!(f, "Invalid token"),
+            ApiError::RetiredKey => write!(f, "Token signed with a retired key"),
+            ApiError::TokenRevoked => write!(f, "Token revoked"),
+            ApiError::Forbidden => write!(f, "Permission denied"),
+            ApiError::ActingForOtherUser => write!(f, "Cannot act on behalf of another user"),
+            ApiError::EmailNotVerified => write!(f, "Email address not verified"),
+            ApiError::NotFound(resource) => write!(f, "{} not found", resource.name()),
+            ApiError::NotQueued => write!(f, "Not in the matchmaking queue"),
+            ApiError::AlreadyQueued => write!(f, "Already in the matchmaking queue"),
+            ApiError::AlreadyInSession => write!(f, "Player already in a session"),
+            ApiError::SessionFull => write!(f, "Session full"),
+            ApiError::NotInSession => write!(f, "Player not in the session"),
+            ApiError::FriendNotInSession => write!(f, "Friend is not in a session"),
+            ApiError::NotFriends => write!(f, "Not friends with this user"),
+            ApiError::SessionPrivate => write!(f, "Session is private"),
+            ApiError::SessionNotJoinable => write!(f, "Session no longer accepts players"),
+            ApiError::SessionClosed => write!(f, "Session is finished or abandoned"),
+            ApiError::MatchInProgress => write!(f, "The match of the session is in progress"),
+            ApiError::InvalidStateTransition(from, to) => write!(f, "Session can't go from {} to {}", from, to),
+            ApiError::NotEnoughPlayers(min_players) => write!(f, "The game mode needs at least {} players", min_players),
+            ApiError::Validation(_) => write!(f, "Invalid fields"),
+            ApiError::Conflict(Some(field)) => write!(f, "{} already in use", field),
+            ApiError::Conflict(None) => write!(f, "Already exists"),
+            ApiError::TooManyRequests(retry_after) => write!(f, "Too many requests, retry in {} seconds", retry_after),
+            ApiError::DatabaseUnavailable => write!(f, "Database unavailable"),
+            ApiError::RedisUnavailable => write!(f, "Failed to connect to Redis"),
+            // Internal details are logged, not sent to the client
+            ApiError::Internal(_) => write!(f, "Internal Server Error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_)
+            | ApiError::InvalidRole
+            | ApiError::InvalidAccountToken
+            | ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidCredentials
+            | ApiError::InvalidRefreshToken
+            | ApiError::InvalidProvisioningToken
+            | ApiError::MissingToken
+            | ApiError::InvalidToken
+            | ApiError::RetiredKey
+            | ApiError::TokenRevoked => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden
+            | ApiError::ActingForOtherUser
+            | ApiError::EmailNotVerified
+            | ApiError::NotFriends
+            | ApiError::SessionPrivate => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) | ApiError::NotQueued | ApiError::NotInSession | ApiError::FriendNotInSession => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_)
+            | ApiError::AlreadyQueued
+            | ApiError::AlreadyInSession
+            | ApiError::SessionFull
+            | ApiError::SessionNotJoinable
+            | ApiError::SessionClosed
+            | ApiError::MatchInProgress
+            | ApiError::InvalidStateTransition(_, _)
+            | ApiError::NotEnoughPlayers(_) => StatusCode::CONFLICT,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::DatabaseUnavailable | ApiError::RedisUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(details) = self {
+            log::error!("{}", details);
+        }
+        let fields = match self {
+            ApiError::Validation(errors) => errors.clone(),
+            ApiError::Conflict(Some(field)) => vec![FieldError::new(field, "taken", self.to_string())],
+            _ => Vec::new(),
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::TooManyRequests(retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+        response.json(ErrorResponse {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                fields,
+            },
+        })
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(_: PoolError) -> ApiError {
+        ApiError::DatabaseUnavailable
+    }
+}
+
+impl From<DieselError> for ApiError {
+    fn from(e: DieselError) -> ApiError {
+        match e {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                // Postgres names unique constraints `<table>_<column>_key`
+                let field = match (info.table_name(), info.constraint_name()) {
+                    (Some(table), Some(constraint)) => constraint
+                        .strip_prefix(&format!("{}_", table))
+                        .and_then(|rest| rest.strip_suffix("_key"))
+                        .map(str::to_string),
+                    _ => None,
+                };
+                ApiError::Conflict(field)
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                ApiError::BadRequest("Invalid reference".to_string())
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ClosedConnection, _) => ApiError::DatabaseUnavailable,
+            e => ApiError::Internal(format!("Database error : {}", e)),
+        }
+    }
+}
+
+impl From<RedisError> for ApiError {
+    fn from(e: RedisError) -> ApiError {
+        if e.is_connection_refusal() || e.is_connection_dropped() || e.is_io_error() || e.is_timeout() {
+            ApiError::RedisUnavailable
+        } else {
+            ApiError::Internal(format!("Redis error : {}", e))
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError {
+        ApiError::Internal(format!("Serialization error : {}", e))
+    }
+}
@@ -1,26 +1,30 @@
 use actix_web::{HttpRequest, HttpResponse, web};
 use actix_web::web::Data;
-use bcrypt::{DEFAULT_COST, hash_with_salt, verify};
-use chrono::{Duration, Utc};
+use chrono::Utc;
 use diesel::{PgConnection, QueryDsl, r2d2, RunQueryDsl};
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
-use jsonwebtoken::{Algorithm, decode, DecodingKey, encode, EncodingKey, Header, Validation};
-use rand::random;
-use redis::{AsyncCommands, Client, RedisError};
-use serde::{Deserialize, Serialize};
+use redis::Client;
+use redis::aio::MultiplexedConnection;
 use uuid::Uuid;
 use web::Json;
 
-use crate::models::{Achievement, AchievementValidation, ConnectSession, DBSession, KdaUpdate, LoginUser, NewUser, Rank, RankUpdate, Session, SessionResponse, User, UserAchievement, AchievementsResponse, RanksResponse, FriendRequest, FriendData, FriendsResponse};
-use crate::schema::{achievements, friend_requests, friends, ranks, roles, sessions, user_achievements, users};
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    role: String,
-    exp: usize,
-}
+use crate::auth::{AuthenticatedUser, ProvisioningToken};
+use crate::errors::{ApiError, FieldError, Resource};
+use crate::jwt::JwtKeys;
+use crate::mailer::{self, Mail, Mailer};
+use crate::matchmaking::{MatchmakingRules, Ticket};
+use crate::models::{AccountAudit, AccountTokenRequest, Achievement, AchievementValidation, ConnectSession, GameMode, GameModesResponse, HeartbeatResponse, KdaUpdate, LeaveSession, LoginUser, LogoutRequest, MatchResult, NewPrivilegedUser, Pagination, PreferredRegionUpdate, NewSession, NewUser, PartyInvite, QueueRequest, RankUpdate, RefreshRequest, Session, SessionAdvertisement, SessionHeartbeat, SessionResponse, SessionState, SessionTeamsResponse, TeamPlayer, TeamResponse, User, UserAchievement, AchievementsResponse, RanksResponse, FriendRequest, FriendData, FriendsResponse, ForgotPasswordRequest, ResetPasswordRequest};
+use crate::passwords::Passwords;
+use crate::queue;
+use crate::ranks;
+use crate::rate_limit::{client_ip, LoginThrottle};
+use crate::rating::{self, RatingSystem};
+use crate::results;
+use crate::schema::{account_audit, achievements, friend_requests, friends, game_modes, roles, sessions, user_achievements, users};
+use crate::session_store::{self, SessionLifetime};
+use crate::tokens::{self, AccountToken};
+use crate::validation::RegistrationPolicy;
 
 type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
@@ -28,1215 +32,1349 @@ pub async fn hello() -> HttpResponse {
     HttpResponse::Ok().body("Hello, world!")
 }
 
-pub async fn register_user(user_data: Json<NewUser>, pool:Data<DbPool>) -> HttpResponse {
+//Public registration, always creates a client account and sends the email verification mail
+pub async fn register_user(
+    user_data: Json<NewUser>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    policy: Data<RegistrationPolicy>,
+    passwords: Data<Passwords>,
+    mailer: Data<dyn Mailer>,
+) -> Result<HttpResponse, ApiError> {
     // Extract user data from request
     let user = user_data.into_inner();
+    policy.validate(&user)?;
+    let email = user.email.clone();
 
-    // Generate salt and hash password
-    let salt: [u8; 16] = random();
-    let hashed_password = hash_with_salt(&user.password,DEFAULT_COST,salt).expect("Failed to hash password");
+    let mut conn = pool.get()?;
+    let (user_id, _) = create_account(&mut conn, &passwords, user, "client")?;
 
-    // Establish a database connection
-    let mut conn = pool.get().expect("Couldn't get db connection from pool");
+    //The account exists at this point, the user can ask for another mail if this one fails
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    if let Err(e) = send_verification_email(&mut con, mailer, user_id, email).await {
+        log::error!("Failed to send the verification mail of {} : {}", user_id, e);
+    }
+
+    Ok(HttpResponse::Ok().body("User registered successfully"))
+}
+
+//Create an account with any role, reserved to admins
+pub async fn create_privileged_user(
+    admin: AuthenticatedUser,
+    user_data: Json<NewPrivilegedUser>,
+    pool: Data<DbPool>,
+    policy: Data<RegistrationPolicy>,
+    passwords: Data<Passwords>,
+) -> Result<HttpResponse, ApiError> {
+    let NewPrivilegedUser { user, role_name } = user_data.into_inner();
+    policy.validate(&user)?;
+
+    let mut conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let (user_id, role_id) = create_account(conn, &passwords, user, &role_name)?;
+        audit_account_creation(conn, user_id, role_id, Some(admin.id), "admin")
+    })?;
+
+    Ok(HttpResponse::Ok().body("User registered successfully"))
+}
+
+//Create the first admin account, nothing is done while an admin exists, returns whether the account was created
+pub fn bootstrap_admin(conn: &mut PgConnection, passwords: &Passwords, policy: &RegistrationPolicy, admin: NewUser) -> Result<bool, ApiError> {
+    let admins: i64 = users::table
+        .inner_join(roles::table)
+        .filter(roles::name.eq("admin"))
+        .count()
+        .get_result(conn)?;
+    if admins > 0 {
+        return Ok(false);
+    }
+
+    policy.validate(&admin)?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let (user_id, role_id) = create_account(conn, passwords, admin, "admin")?;
+        audit_account_creation(conn, user_id, role_id, None, "bootstrap")
+    })?;
+    Ok(true)
+}
+
+//Revoke every token of an account at once, for banned or compromised accounts, reserved to admins
+pub async fn revoke_user_tokens(
+    admin: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    user_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user_id.into_inner();
+    let mut conn = pool.get()?;
+    let exists: i64 = users::table
+        .filter(users::id.eq(user_id))
+        .count()
+        .get_result(&mut conn)?;
+    if exists == 0 {
+        return Err(ApiError::NotFound(Resource::User));
+    }
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    tokens::revoke_user(&mut con, &user_id).await?;
+    log::info!("Tokens of {} revoked by admin {}", user_id, admin.id);
+
+    Ok(HttpResponse::Ok().body("User tokens revoked successfully"))
+}
+
+//Create a server account, authorized by the provisioning token instead of a user token
+pub async fn provision_server(
+    req: HttpRequest,
+    user_data: Json<NewUser>,
+    pool: Data<DbPool>,
+    policy: Data<RegistrationPolicy>,
+    passwords: Data<Passwords>,
+    provisioning: Data<ProvisioningToken>,
+) -> Result<HttpResponse, ApiError> {
+    provisioning.check(&req)?;
+    let user = user_data.into_inner();
+    policy.validate(&user)?;
+
+    let mut conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let (user_id, role_id) = create_account(conn, &passwords, user, "server")?;
+        audit_account_creation(conn, user_id, role_id, None, "provisioning_token")
+    })?;
+
+    Ok(HttpResponse::Ok().body("User registered successfully"))
+}
+
+//Hash the password and insert the user with the given role, returns the ids of the user and of the role
+fn create_account(conn: &mut PgConnection, passwords: &Passwords, user: NewUser, role_name: &str) -> Result<(Uuid, Uuid), ApiError> {
+    let hashed_password = passwords.hash(&user.password)?;
 
     // Find role_id by role name
-    let role_id: Uuid = match roles::table
+    let role_id: Uuid = roles::table
         .select(roles::id)
-        .filter(roles::name.eq(&user.role_name))
-        .first(&mut conn) // Lock the Mutex and unwrap to get the PgConnection
-    {
-        Ok(id) => id,
-        Err(_) => {
-            // If the role does not exist, you might want to handle this case appropriately
-            return HttpResponse::BadRequest().body("Invalid role name provided");
-        }
-    };
+        .filter(roles::name.eq(role_name))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::InvalidRole)?;
 
-    //Find the id of the rank bronze
-    let rank_id: Uuid = match ranks::table
-        .select(ranks::id)
-        .filter(ranks::name.eq("Bronze"))
-        .first(&mut conn) // Lock the Mutex and unwrap to get the PgConnection
-    {
-        Ok(id) => id,
-        Err(_) => {
-            // If the role does not exist, you might want to handle this case appropriately
-            return HttpResponse::BadRequest().body("Invalid role name provided");
-        }
-    };
+    //New players start in the default rank
+    let rank_id = ranks::default_rank(conn)?;
 
     // Create new user
     let new_user = User {
         id: Uuid::new_v4(),
         username: user.username,
         email: user.email,
-        password: hashed_password.to_string(),
-        salt: salt.to_vec().iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        password: hashed_password,
         kda: 0.0,
+        kills: 0,
+        deaths: 0,
+        assists: 0,
         nb_games: 0,
         role_id,
-        rank_id
+        rank_id,
+        //Only client accounts are created by their owner
+        email_verified: role_name != "client",
+        preferred_region: None,
+        rating: rating::DEFAULT_RATING as f32,
+        rating_deviation: rating::DEFAULT_DEVIATION as f32,
+        rating_volatility: rating::DEFAULT_VOLATILITY as f32,
     };
 
-    // Insert new user into the database
-    return match diesel::insert_into(users::table)
+    // Insert new user into the database, a taken username or email is reported as a conflict on that field
+    diesel::insert_into(users::table)
         .values(&new_user)
-        .execute(&mut conn) // Lock the Mutex and unwrap to get the PgConnection
-    {
-        Ok(_) => {
-            HttpResponse::Ok().body("User registered successfully")
-        }
-        Err(_) => {
-            HttpResponse::BadRequest().body("Error inserting user into database")
+        .execute(conn)?;
+
+    Ok((new_user.id, role_id))
+}
+
+//Keep a record of every account created outside of the public registration
+fn audit_account_creation(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    role_id: Uuid,
+    created_by: Option<Uuid>,
+    method: &str,
+) -> Result<(), ApiError> {
+    diesel::insert_into(account_audit::table)
+        .values(&AccountAudit {
+            id: Uuid::new_v4(),
+            user_id,
+            role_id,
+            created_by,
+            method: method.to_string(),
+        })
+        .execute(conn)?;
+    log::info!("Account {} created through {}", user_id, method);
+    Ok(())
+}
+
+async fn send_verification_email(
+    con: &mut MultiplexedConnection,
+    mailer: Data<dyn Mailer>,
+    user_id: Uuid,
+    email: String,
+) -> Result<(), ApiError> {
+    let token = tokens::issue_account_token(con, AccountToken::EmailVerification, &user_id).await?;
+    mailer::deliver(mailer, Mail {
+        to: email,
+        subject: "Verify your email address".to_string(),
+        body: format!("Use this code to verify your email address, it is valid for 24 hours :\n\n{}", token),
+    }).await
+}
+
+//Send a new verification mail to the user of the token
+pub async fn resend_verification_email(
+    user: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    mailer: Data<dyn Mailer>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    let (email, email_verified): (String, bool) = users::table
+        .select((users::email, users::email_verified))
+        .filter(users::id.eq(&user.id))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+    if email_verified {
+        return Ok(HttpResponse::Ok().body("Email already verified"));
+    }
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    send_verification_email(&mut con, mailer, user.id, email).await?;
+    Ok(HttpResponse::Ok().body("Verification mail sent"))
+}
+
+pub async fn verify_email(
+    token_data: Json<AccountTokenRequest>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+) -> Result<HttpResponse, ApiError> {
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let user_id = tokens::take_account_token(&mut con, AccountToken::EmailVerification, &token_data.token)
+        .await?
+        .ok_or(ApiError::InvalidAccountToken)?;
+
+    let mut conn = pool.get()?;
+    diesel::update(users::table.filter(users::id.eq(&user_id)))
+        .set(users::email_verified.eq(true))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Email verified successfully"))
+}
+
+//Send a password reset mail, the answer is the same whether the email is registered or not
+pub async fn forgot_password(
+    request: Json<ForgotPasswordRequest>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    mailer: Data<dyn Mailer>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    let user_id: Option<Uuid> = users::table
+        .select(users::id)
+        .filter(users::email.eq(&request.email))
+        .first(&mut conn)
+        .optional()?;
+
+    //A failure is only logged, answering with an error for registered emails alone would tell which ones are
+    if let Some(user_id) = user_id {
+        let sent = async {
+            let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+            let token = tokens::issue_account_token(&mut con, AccountToken::PasswordReset, &user_id).await?;
+            mailer::deliver(mailer, Mail {
+                to: request.into_inner().email,
+                subject: "Reset your password".to_string(),
+                body: format!("Use this code to choose a new password, it is valid for 1 hour :\n\n{}\n\nIgnore this mail if you did not ask for it.", token),
+            }).await
+        };
+        if let Err(e) = sent.await {
+            log::error!("Failed to send the password reset mail of {} : {}", user_id, e);
         }
     }
+
+    Ok(HttpResponse::Ok().body("If this email is registered, a reset mail has been sent"))
 }
 
-pub async fn login_user(user_data: Json<LoginUser>, pool:Data<DbPool>) -> HttpResponse {
+//Choose a new password with a reset token, every session of the user is logged out
+pub async fn reset_password(
+    reset_data: Json<ResetPasswordRequest>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    policy: Data<RegistrationPolicy>,
+    passwords: Data<Passwords>,
+) -> Result<HttpResponse, ApiError> {
+    let reset_data = reset_data.into_inner();
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let mut conn = pool.get()?;
+
+    //Check the new password before consuming the token, so a rejected password can be corrected
+    let user_id = tokens::peek_account_token(&mut con, AccountToken::PasswordReset, &reset_data.token)
+        .await?
+        .ok_or(ApiError::InvalidAccountToken)?;
+    let username: String = users::table
+        .select(users::username)
+        .filter(users::id.eq(&user_id))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::InvalidAccountToken)?;
+    policy.validate_password(&reset_data.password, &username)?;
+
+    if tokens::take_account_token(&mut con, AccountToken::PasswordReset, &reset_data.token).await? != Some(user_id) {
+        return Err(ApiError::InvalidAccountToken);
+    }
+
+    let hashed_password = passwords.hash(&reset_data.password)?;
+    diesel::update(users::table.filter(users::id.eq(&user_id)))
+        .set(users::password.eq(hashed_password))
+        .execute(&mut conn)?;
+    tokens::revoke_user(&mut con, &user_id).await?;
+
+    Ok(HttpResponse::Ok().body("Password reset successfully"))
+}
+
+pub async fn login_user(
+    req: HttpRequest,
+    user_data: Json<LoginUser>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    keys: Data<JwtKeys>,
+    throttle: Data<LoginThrottle>,
+    passwords: Data<Passwords>,
+) -> Result<HttpResponse, ApiError> {
     let user_data = user_data.into_inner();
+    let ip = client_ip(&req);
+
+    //Refuse the attempt while the username or the IP is locked out
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    throttle.check(&mut con, &user_data.username, &ip).await?;
 
-    let mut conn = pool.get().expect("Couldn't get db connection from pool");
+    let mut conn = pool.get()?;
 
     // Retrieve user from database
-    let user: User = match users::table
+    let user: Option<User> = users::table
         .filter(users::username.eq(&user_data.username))
         .first(&mut conn)
-    {
-        Ok(user) => user,
-        Err(_) => {
-            return HttpResponse::Unauthorized().body("Invalid username or password");
+        .optional()?;
+
+    // Verify password, a malformed hash is treated as a wrong password
+    let (user, verification) = match user {
+        Some(user) => {
+            let verification = passwords.verify(&user_data.password, &user.password);
+            (user, verification)
+        }
+        None => {
+            throttle.record_failure(&mut con, &user_data.username, &ip).await?;
+            return Err(ApiError::InvalidCredentials);
         }
     };
+    if !verification.valid {
+        throttle.record_failure(&mut con, &user_data.username, &ip).await?;
+        return Err(ApiError::InvalidCredentials);
+    }
+    throttle.record_success(&mut con, &user_data.username).await?;
+
+    //Upgrade hashes made with an older scheme or older parameters, the login goes on if it fails
+    if verification.needs_rehash {
+        let upgraded = passwords.hash(&user_data.password).and_then(|hashed_password| {
+            diesel::update(users::table.filter(users::id.eq(&user.id)))
+                .set(users::password.eq(hashed_password))
+                .execute(&mut conn)
+                .map_err(ApiError::from)
+        });
+        if let Err(e) = upgraded {
+            log::error!("Failed to upgrade the password hash of {} : {}", user.id, e);
+        }
+    }
 
     //Get the role name from the roles database
-    let role_name: String = match roles::table
+    let role_name: String = roles::table
         .select(roles::name)
         .filter(roles::id.eq(&user.role_id))
         .first(&mut conn)
-    {
-        Ok(name) => name,
-        Err(_) => {
-            return HttpResponse::BadRequest().body("Invalid role id");
-        }
-    };
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Role))?;
 
-    // Verify password
-    let is_valid_password = verify(&user_data.password, &user.password).unwrap();
-    if is_valid_password {
-        // Generate JWT token
-        let expiration = Utc::now() + Duration::seconds(3600);
-        let claims = Claims {
-            sub: user.id.to_string(),
-            role: role_name,
-            exp: expiration.timestamp() as usize,
-        };
+    // Generate the access and refresh tokens
+    let token_response = tokens::issue_tokens(&mut con, &keys, user.id, role_name).await?;
+    Ok(HttpResponse::Ok().json(token_response))
+}
+
+//Exchange a refresh token for a new access token, the refresh token is rotated on every use
+pub async fn refresh_token(
+    refresh_data: Json<RefreshRequest>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    keys: Data<JwtKeys>,
+) -> Result<HttpResponse, ApiError> {
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+
+    let refresh_data = tokens::take_refresh_token(&mut con, &refresh_data.refresh_token)
+        .await?
+        .ok_or(ApiError::InvalidRefreshToken)?;
 
-        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret("secret".as_ref())).unwrap();
+    let mut conn = pool.get()?;
 
-        HttpResponse::Ok().json(token)
+    //Get the current role of the user, the account may have been removed since the last login
+    let role_name: String = users::table
+        .inner_join(roles::table)
+        .select(roles::name)
+        .filter(users::id.eq(&refresh_data.user_id))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::InvalidRefreshToken)?;
+
+    let token_response = tokens::issue_tokens(&mut con, &keys, refresh_data.user_id, role_name).await?;
+    Ok(HttpResponse::Ok().json(token_response))
+}
+
+//Revoke the access token used for the request and the given refresh token, or every token of the user
+pub async fn logout(
+    user: AuthenticatedUser,
+    logout_data: Json<LogoutRequest>,
+    redis: Data<Client>,
+) -> Result<HttpResponse, ApiError> {
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+
+    let logout_data = logout_data.into_inner();
+    if logout_data.all_sessions {
+        tokens::revoke_user(&mut con, &user.id).await?;
     } else {
-        HttpResponse::Unauthorized().body("Invalid username or password")
+        if let Some(refresh_token) = logout_data.refresh_token {
+            //Only drop refresh tokens that belong to the caller, the token of someone else is left untouched
+            if !tokens::take_own_refresh_token(&mut con, &refresh_token, &user.id).await? {
+                return Err(ApiError::Forbidden);
+            }
+        }
+        tokens::revoke_access_token(&mut con, &user.claims).await?;
     }
+
+    Ok(HttpResponse::Ok().body("Logged out successfully"))
 }
 
 pub async fn register_session(
-    req: HttpRequest,
-    session: Json<Session>,
+    server: AuthenticatedUser,
+    session: Json<NewSession>,
     redis: Data<Client>,
     pool: Data<DbPool>,
-) -> HttpResponse {
-    // Extract JWT token from request headers
-    let token_validation = validate_token(req, "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            match redis.get_ref().get_multiplexed_async_connection().await {
-                Ok(mut con) => {
-                    // Store session data in Redis using the session id as the key
-                    let session_id: Uuid = Uuid::new_v4();
-                    let _: Result<(), RedisError> = con
-                        .set(session_id.to_string(), serde_json::to_string(&session.into_inner()).unwrap())
-                        .await;
-                    //Add the session to a table in the database
-                    let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-                    // Insert new user into the database with the session id
-                    diesel::insert_into(sessions::table)
-                        .values(sessions::id.eq(session_id))
-                        .execute(&mut conn) // Lock the Mutex and unwrap to get the PgConnection
-                        .expect("Error inserting user into database");
-
-                    HttpResponse::Ok().body("Session registered successfully")
-                }
-                Err(_) => HttpResponse::InternalServerError().body("Failed to connect to Redis"),
-            }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+    lifetime: Data<SessionLifetime>,
+) -> Result<HttpResponse, ApiError> {
+    let session = session.into_inner();
+
+    //Get the game mode hosted by the server
+    let mut conn = pool.get()?;
+    let game_mode = session_store::find_game_mode(&mut conn, &session.game_mode)?;
+
+    //Add the session to a table in the database
+    let session_id: Uuid = Uuid::new_v4();
+    diesel::insert_into(sessions::table)
+        .values((
+            sessions::id.eq(session_id),
+            sessions::game_mode_id.eq(game_mode.id),
+            sessions::server_id.eq(server.id),
+        ))
+        .execute(&mut conn)?;
+
+    // Store session data in Redis using the session id as the key, it expires if the server stops sending heartbeats
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let session = Session::new(session, &game_mode);
+    session_store::store_session(&mut con, &session_id, &session, lifetime.ttl()).await?;
+
+    Ok(HttpResponse::Ok().json(SessionResponse {
+        session_id,
+        server_address: session.server_address,
+        port: session.port,
+        team: None,
+    }))
 }
 
-pub(crate) async fn request_session(
-    req: HttpRequest,
+//Change the address, map, region or player count advertised for a session by its server
+pub async fn update_session(
+    server: AuthenticatedUser,
+    pool: Data<DbPool>,
     redis: Data<Client>,
+    session_id: web::Path<Uuid>,
+    advertisement: Json<SessionAdvertisement>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    session_store::advertise(&mut conn, &mut con, &server.id, &session_id, &advertisement).await?;
+
+    Ok(HttpResponse::Ok().body("Session updated successfully"))
+}
+
+//Put the player in the matchmaking queue, the matchmaker places them in a session in the background
+pub async fn join_queue(
+    user: AuthenticatedUser,
+    queue_data: Json<QueueRequest>,
     pool: Data<DbPool>,
-    other_username: web::Path<String>,
-) -> HttpResponse {
-    // Extract JWT token from request headers
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            //Get all non-empty the sessions from the database
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-            let sessions: Vec<DBSession> = sessions::table
-                .filter(sessions::is_empty.eq(false))
-                .load(&mut conn)
-                .expect("Error loading sessions");
-
-            let session_id: Uuid;
-            if sessions.len() == 0 {
-                //Get the first empty session from the database
-                let empty_session: Uuid = match sessions::table
-                    .select(sessions::id)
-                    .filter(sessions::is_empty.eq(true))
-                    .first(&mut conn)
-                {
-                    Ok(id) => id,
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("No session available");
-                    }
-                };
-                session_id = empty_session;
-            } else {
-                //Get the user data from the database
-                let user_data: User  = match users::table
-                    .filter(users::username.eq(other_username.into_inner()))
-                    .first(&mut conn)
-                {
-                    Ok(id) => id,
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Invalid username");
-                    }
-                };
-
-                //Get the rank of the user
-                let _rank: Rank = match ranks::table
-                    .filter(ranks::id.eq(&user_data.rank_id))
-                    .first(&mut conn)
-                {
-                    Ok(id) => id,
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Invalid rank id");
-                    }
-                };
-
-                //Get the average kda of the user
-                let kda: f32 = user_data.kda;
-
-                //Get the session with the closest average kda to the user
-                let mut closest_session: DBSession = sessions[0].clone();
-                let mut closest_distance: f32 = (kda - sessions[0].average_kda).abs();
-                for session in sessions.iter() {
-                    let distance = (kda - session.average_kda).abs();
-                    if distance < closest_distance {
-                        closest_distance = distance;
-                        closest_session = session.clone();
-                    }
-                }
-                session_id = closest_session.id;
-            }
+    redis: Data<Client>,
+    rules: Data<MatchmakingRules>,
+) -> Result<HttpResponse, ApiError> {
+    let queue_data = queue_data.into_inner();
+    let mut conn = pool.get()?;
+    let game_mode = session_store::find_game_mode(&mut conn, &queue_data.game_mode)?;
+    let players = party_members(&mut conn, &user.id, &queue_data.party, &game_mode)?;
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+
+    //Friends are only queued once they accepted to join the party of the player
+    let mut errors = Vec::new();
+    for (index, member) in players.iter().enumerate().skip(1) {
+        if queue::party_leader(&mut con, member).await? != Some(user.id) {
+            errors.push(FieldError::new(
+                &format!("party[{}]", index - 1),
+                "not_in_party",
+                "Player did not accept to join the party".to_string(),
+            ));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    if game_mode.ranked {
+        for player in players.iter() {
+            require_verified_email(&mut conn, player)?;
+        }
+    }
+
+    //Without pings nor region in the request the players are placed in their preferred region
+    let region = match queue_data.region {
+        Some(region) => Some(region),
+        None => users::table
+            .select(users::preferred_region)
+            .filter(users::id.eq(user.id))
+            .first(&mut conn)
+            .optional()?
+            .ok_or(ApiError::NotFound(Resource::User))?,
+    };
 
+    let ticket = Ticket {
+        id: Uuid::new_v4(),
+        game_mode: game_mode.name,
+        skill: session_store::average_skill(&mut conn, &players, rules.skill)?,
+        tier: session_store::average_tier(&mut conn, &players)?,
+        pings: queue_data.pings,
+        region,
+        players,
+        enqueued_at: Utc::now().timestamp(),
+    };
+
+    queue::enqueue(&mut con, &ticket).await?;
+    Ok(HttpResponse::Accepted().json(queue::status(&mut con, &rules, &user.id).await?))
+}
 
-            // Get the session from Redis
-            match redis.get_ref().get_multiplexed_async_connection().await {
-                Ok(mut con) => {
-                    // Retrieve session data from Redis
-                    let session_data: Result<String, RedisError> = con.get(session_id.to_string()).await;
-                    match session_data {
-                        Ok(data) => {
-                            let session: Session = serde_json::from_str(&data).unwrap();
-                            let response : SessionResponse = SessionResponse {
-                                session_id,
-                                server_address: session.server_address
-                            };
-                            HttpResponse::Ok().json(response)
-                        }
-                        Err(_) => HttpResponse::NotFound().body("Session not found"),
-                    }
-                }
-                Err(_) => HttpResponse::InternalServerError().body("Failed to connect to Redis"),
+//Players of the ticket of a leader : the leader then the friends they list, as many as one team of the game mode holds
+fn party_members(conn: &mut PgConnection, leader: &Uuid, party: &[String], game_mode: &GameMode) -> Result<Vec<Uuid>, ApiError> {
+    let mut players = vec![*leader];
+    let mut errors = Vec::new();
+    for (index, username) in party.iter().enumerate() {
+        let field = format!("party[{}]", index);
+        let friend_id: Option<Uuid> = friends::table
+            .inner_join(users::table)
+            .select(friends::friend_id)
+            .filter(friends::user_id.eq(leader))
+            .filter(users::username.eq(username))
+            .first(conn)
+            .optional()?;
+        match friend_id {
+            None => errors.push(FieldError::new(&field, "not_friends", "Not friends with this user".to_string())),
+            Some(friend_id) if players.contains(&friend_id) => {
+                errors.push(FieldError::new(&field, "duplicate", "Player listed more than once".to_string()))
             }
+            Some(friend_id) => players.push(friend_id),
         }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
     }
+
+    let team_size = (game_mode.max_players + game_mode.team_count - 1) / game_mode.team_count;
+    if players.len() as i32 > team_size {
+        errors.push(FieldError::new(
+            "party",
+            "too_large",
+            format!("A party of {} can have at most {} players", game_mode.name, team_size),
+        ));
+    }
+
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    Ok(players)
+}
+
+//Poll the matchmaking queue, answers with the session once one is found
+pub async fn get_queue_status(
+    user: AuthenticatedUser,
+    redis: Data<Client>,
+    rules: Data<MatchmakingRules>,
+) -> Result<HttpResponse, ApiError> {
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    Ok(HttpResponse::Ok().json(queue::status(&mut con, &rules, &user.id).await?))
+}
+
+//Region the matchmaker uses for the player when the client sends no ping, null to play anywhere
+pub async fn update_preferred_region(
+    user: AuthenticatedUser,
+    pool: Data<DbPool>,
+    region_data: Json<PreferredRegionUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let region = region_data.into_inner().region.map(|region| region.trim().to_string());
+    if region.as_ref().is_some_and(|region| region.is_empty() || region.chars().count() > 50) {
+        return Err(ApiError::Validation(vec![FieldError::new(
+            "region",
+            "invalid_length",
+            "Region must be between 1 and 50 characters long".to_string(),
+        )]));
+    }
+
+    let mut conn = pool.get()?;
+    diesel::update(users::table.filter(users::id.eq(user.id)))
+        .set(users::preferred_region.eq(region))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Preferred region updated successfully"))
+}
+
+pub async fn leave_queue(user: AuthenticatedUser, redis: Data<Client>) -> Result<HttpResponse, ApiError> {
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    queue::leave(&mut con, &user.id).await?;
+    Ok(HttpResponse::Ok().body("Left the matchmaking queue"))
+}
+
+//Invite a friend to the party of the player, the friend can be queued along with the player once they accept
+pub async fn invite_to_party(
+    user: AuthenticatedUser,
+    invite: Json<PartyInvite>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    let friend_id: Uuid = friends::table
+        .inner_join(users::table)
+        .select(friends::friend_id)
+        .filter(friends::user_id.eq(user.id))
+        .filter(users::username.eq(&invite.username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFriends)?;
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    queue::invite(&mut con, &user.id, &friend_id).await?;
+    Ok(HttpResponse::Ok().body("Party invite sent successfully"))
+}
+
+//Join the party of a friend who invited the player
+pub async fn accept_party_invite(
+    user: AuthenticatedUser,
+    invite: Json<PartyInvite>,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    let leader_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&invite.username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    queue::accept_invite(&mut con, &user.id, &leader_id).await?;
+    Ok(HttpResponse::Ok().body("Party joined successfully"))
+}
+
+pub async fn leave_party(user: AuthenticatedUser, redis: Data<Client>) -> Result<HttpResponse, ApiError> {
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    queue::leave_party(&mut con, &user.id).await?;
+    Ok(HttpResponse::Ok().body("Left the party"))
 }
 
 //Connect the  player to a session by adding his id to the session
 pub async fn connect_to_session(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     pool: Data<DbPool>,
     redis: Data<Client>,
+    rules: Data<MatchmakingRules>,
     connection_data: Json<ConnectSession>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            //Get the player_id from the username
-            let player_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&connection_data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            let mut session: Session;
-            //Add the user to the session in redis
-            match redis.get_ref().get_multiplexed_async_connection().await {
-                Ok(mut con) => {
-                    // Retrieve session data from Redis
-                    let session_data: Result<String, RedisError> = con.get(connection_data.session_id.to_string()).await;
-                    match session_data {
-                        Ok(data) => {
-                            session = serde_json::from_str(&data).unwrap();
-                            session.players.push(String::from(player_id));
-                            let _: Result<(), RedisError> = con
-                                .set(connection_data.session_id.to_string(), serde_json::to_string(&session).unwrap())
-                                .await;
-                        }
-                        Err(_) => return HttpResponse::NotFound().body("Session not found")
-                    }
-                }
-                Err(_) => return HttpResponse::InternalServerError().body("Failed to connect to Redis")
-            }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
 
-            //the session is full
-            if session.players.len() == 6 {
-                //Remove the session from the database
-                match diesel::delete(sessions::table
-                    .filter(sessions::id.eq(connection_data.session_id)))
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error deleting session");
-                    }
-                }
-
-                //Remove the session from redis
-                match redis.get_ref().get_multiplexed_async_connection().await {
-                    Ok(mut con) => {
-                        let _: Result<(), RedisError> = con.del(connection_data.session_id.to_string()).await;
-                    }
-                    Err(_) => return HttpResponse::InternalServerError().body("Failed to connect to Redis")
-                }
-                return HttpResponse::Ok().body("Succesfully connected : Session full")
-            }
+    //Get the player_id from the token, or from the username for server and admin tokens
+    let player_id: Uuid = acting_user_id(&user, connection_data.username.as_deref(), &mut conn)?;
 
-            //Get the kda of the player
-            let kda: f32 = match users::table
-                .select(users::kda)
-                .filter(users::id.eq(&player_id))
-                .first(&mut conn)
-            {
-                Ok(kda) => kda,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid user");
-                }
-            };
-
-            //TODO : check if the session is empty before updating the kda
-            let mut session_kda: f32;
-            if(session.players.len() == 1){
-                //Make the session not empty
-                session_kda = kda;
-                match diesel::update(sessions::table
-                    .filter(sessions::id.eq(connection_data.session_id)))
-                    .set(sessions::is_empty.eq(false),)
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error updating session");
-                    }
-                }
-            } else {
-                //Get the mean Of the kda of the players in the session
-                let mut total_kda: f32 = 0.0;
-                for player in session.players.iter() {
-                    let player_id: Uuid = Uuid::parse_str(player).unwrap();
-                    let player_kda: f32 = match users::table
-                        .select(users::kda)
-                        .filter(users::id.eq(&player_id))
-                        .first(&mut conn)
-                    {
-                        Ok(kda) => kda,
-                        Err(_) => {
-                            return HttpResponse::BadRequest().body("Invalid user");
-                        }
-                    };
-                    total_kda += player_kda;
-                }
-                session_kda = total_kda / session.players.len() as f32;
-            }
+    //Ranked sessions are reserved to verified accounts
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let session = session_store::load_session(&mut con, &connection_data.session_id).await?;
+    if session_store::find_game_mode(&mut conn, &session.game_mode)?.ranked {
+        require_verified_email(&mut conn, &player_id)?;
+    }
 
-            //Update the session with the new kda
-            match diesel::update(sessions::table
-                .filter(sessions::id.eq(connection_data.session_id)))
-                .set(sessions::average_kda.eq(session_kda),)
-                .execute(&mut conn)
-            {
-                Ok(_) => {
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error updating session");
-                }
-            };
-
-            //Update the session with the rank of the player
-            let player_rank_id: Uuid = match users::table
-                .select(users::rank_id)
-                .filter(users::username.eq(&connection_data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            match diesel::update(sessions::table
-                .filter(sessions::id.eq(connection_data.session_id)))
-                .set(sessions::average_rank.eq(player_rank_id),)
-                .execute(&mut conn)
-            {
-                Ok(_) => {
-                    HttpResponse::Ok().body("Player connected to session successfully")
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error updating session");
-                }
-            }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
+    //Add the user to the session in redis
+    if session_store::join_session(&mut conn, &mut con, &connection_data.session_id, &[player_id], rules.skill).await?.session_full {
+        return Ok(HttpResponse::Ok().body("Player connected to session successfully : Session now full"))
     }
+
+    Ok(HttpResponse::Ok().body("Player connected to session successfully"))
 }
 
-//Remove a session
-pub async fn remove_session(
-    req: HttpRequest,
+//Players of each team of a session, for the game server hosting it
+pub async fn get_session_teams(
+    server: AuthenticatedUser,
     pool: Data<DbPool>,
     redis: Data<Client>,
     session_id: web::Path<Uuid>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let session_id = session_id.into_inner();
-            //Remove the session from the database
-            match diesel::delete(sessions::table
-                .filter(sessions::id.eq(session_id)))
-                .execute(&mut conn)
-            {
-                Ok(_) => {}
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error deleting session");
-                }
-            }
+) -> Result<HttpResponse, ApiError> {
+    let session_id = session_id.into_inner();
+    let mut conn = pool.get()?;
+    session_store::require_owner(&mut conn, &server.id, &session_id)?;
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let session = session_store::load_session(&mut con, &session_id).await?;
+
+    let mut teams = Vec::new();
+    for (team, players) in session.teams.iter().enumerate() {
+        let ids: Vec<Uuid> = players.iter().filter_map(|player| Uuid::parse_str(player).ok()).collect();
+        let usernames: Vec<(Uuid, String)> = users::table
+            .select((users::id, users::username))
+            .filter(users::id.eq_any(&ids))
+            .load(&mut conn)?;
+        //Keep the order in which the players joined
+        let players = ids
+            .iter()
+            .filter_map(|id| usernames.iter().find(|(user_id, _)| user_id == id))
+            .map(|(id, username)| TeamPlayer { id: *id, username: username.clone() })
+            .collect();
+        teams.push(TeamResponse {
+            team,
+            skill: session.team_skill.get(team).copied().unwrap_or(0.0),
+            players,
+        });
+    }
 
-            //Remove the session from redis
-            match redis.get_ref().get_multiplexed_async_connection().await {
-                Ok(mut con) => {
-                    let _: Result<(), RedisError> = con.del(session_id.to_string()).await;
-                }
-                Err(_) => return HttpResponse::InternalServerError().body("Failed to connect to Redis")
-            }
+    Ok(HttpResponse::Ok().json(SessionTeamsResponse {
+        session_id,
+        game_mode: session.game_mode,
+        teams,
+    }))
+}
 
-            HttpResponse::Ok().body("Session removed successfully")
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
+//A player leaves a session, or the server of the session kicks one of its players
+pub async fn leave_session(
+    user: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    rules: Data<MatchmakingRules>,
+    session_id: web::Path<Uuid>,
+    leave_data: Json<LeaveSession>,
+) -> Result<HttpResponse, ApiError> {
+    let session_id = session_id.into_inner();
+    let mut conn = pool.get()?;
+    let player_id = acting_user_id(&user, leave_data.username.as_deref(), &mut conn)?;
+    if user.role == "server" {
+        session_store::require_owner(&mut conn, &user.id, &session_id)?;
     }
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    session_store::leave_session(&mut conn, &mut con, &session_id, &player_id, rules.skill).await?;
+
+    Ok(HttpResponse::Ok().body("Player left the session successfully"))
 }
 
-pub async fn validate_achievement(user_data: Json<AchievementValidation>, pool:Data<DbPool>, http_request: HttpRequest) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(http_request, "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Extract user data from request
-            let user_data = user_data.into_inner();
-
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Check if user exists
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&user_data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            let user_achievement = UserAchievement {
-                user_id,
-                achievement_id: user_data.achievement_id,
-            };
-
-            match diesel::insert_into(user_achievements::table)
-                .values(&user_achievement)
-                .execute(&mut conn)
-            {
-                Ok(_) => {
-                    HttpResponse::Ok().body("Achievement validated successfully")
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error inserting user achievement into database");
-                }
-            }
+//Join the session a friend is playing in
+pub async fn join_friend(
+    user: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    rules: Data<MatchmakingRules>,
+    user_data: Json<FriendRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+
+    let data = user_data.into_inner();
+    let player_id: Uuid = acting_user_id(&user, data.username.as_deref(), &mut conn)?;
+
+    let friend_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&data.friend_username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Friend))?;
+
+    //Friendships are stored in both directions
+    let are_friends: i64 = friends::table
+        .filter(friends::user_id.eq(player_id))
+        .filter(friends::friend_id.eq(friend_id))
+        .count()
+        .get_result(&mut conn)?;
+    if are_friends == 0 {
+        return Err(ApiError::NotFriends);
+    }
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let (session_id, session) = session_store::current_session(&mut con, &friend_id)
+        .await?
+        .ok_or(ApiError::FriendNotInSession)?;
+    if session.private {
+        return Err(ApiError::SessionPrivate);
+    }
+    if session_store::find_game_mode(&mut conn, &session.game_mode)?.ranked {
+        require_verified_email(&mut conn, &player_id)?;
+    }
+
+    let joined = session_store::join_session(&mut conn, &mut con, &session_id, &[player_id], rules.skill).await?;
+    Ok(HttpResponse::Ok().json(SessionResponse {
+        session_id,
+        server_address: session.server_address,
+        port: session.port,
+        team: Some(joined.team),
+    }))
+}
+
+pub async fn get_game_modes(pool: Data<DbPool>) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    let game_modes: Vec<GameMode> = game_modes::table
+        .order(game_modes::max_players)
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(GameModesResponse { game_modes }))
+}
+
+//Sent periodically by the server hosting a session, sessions without heartbeat are abandoned by the reaper
+pub async fn session_heartbeat(
+    server: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    lifetime: Data<SessionLifetime>,
+    session_id: web::Path<Uuid>,
+    heartbeat: Json<SessionHeartbeat>,
+) -> Result<HttpResponse, ApiError> {
+    let session_id = session_id.into_inner();
+    let mut conn = pool.get()?;
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    let state = session_store::heartbeat(&mut conn, &mut con, &lifetime, &server.id, &session_id, heartbeat.state).await?;
+
+    Ok(HttpResponse::Ok().json(HeartbeatResponse {
+        session_id,
+        state,
+        heartbeat_timeout: lifetime.heartbeat_timeout,
+    }))
+}
+
+//Apply the result of a match sent by the server of its session, then close the session
+//
+//Sending the result of a session again changes nothing, so servers can retry after a failure
+pub async fn submit_match_result(
+    server: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    lifetime: Data<SessionLifetime>,
+    rating_system: Data<RatingSystem>,
+    session_id: web::Path<Uuid>,
+    result: Json<MatchResult>,
+) -> Result<HttpResponse, ApiError> {
+    let session_id = session_id.into_inner();
+    let mut conn = pool.get()?;
+    session_store::require_owner(&mut conn, &server.id, &session_id)?;
+
+    //A result already applied is not checked again, the session is only closed in case that failed the first time
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    if !results::is_recorded(&mut conn, &session_id)? {
+        //Only a match that started has a result
+        match session_store::session_state(&mut conn, &session_id)? {
+            SessionState::InProgress => {}
+            SessionState::Finished | SessionState::Abandoned => return Err(ApiError::SessionClosed),
+            state => return Err(ApiError::InvalidStateTransition(state.as_str(), SessionState::Finished.as_str())),
         }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
+        let session = session_store::load_session(&mut con, &session_id).await?;
+        results::apply_result(&mut conn, &rating_system, &server.id, &session_id, &session, &result)?;
     }
+    session_store::finish_session(&mut conn, &mut con, &lifetime, &session_id).await?;
+
+    Ok(HttpResponse::Ok().body("Match result recorded successfully"))
+}
+
+//Recent matches of a player, by pages starting at 1
+pub async fn get_player_matches(
+    pool: Data<DbPool>,
+    username: web::Path<String>,
+    pagination: web::Query<Pagination>,
+) -> Result<HttpResponse, ApiError> {
+    let page = pagination.page.unwrap_or(1).max(1);
+    let per_page = pagination
+        .per_page
+        .unwrap_or(results::DEFAULT_PAGE_SIZE)
+        .clamp(1, results::MAX_PAGE_SIZE);
+
+    let mut conn = pool.get()?;
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(username.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    Ok(HttpResponse::Ok().json(results::player_matches(&mut conn, &user_id, page, per_page)?))
+}
 
+pub async fn get_match(
+    pool: Data<DbPool>,
+    match_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let mut conn = pool.get()?;
+    Ok(HttpResponse::Ok().json(results::match_details(&mut conn, &match_id)?))
+}
+
+//Remove a session
+//The session is abandoned and its players freed, its data expires from Redis like the one of any session that is over
+pub async fn remove_session(
+    server: AuthenticatedUser,
+    pool: Data<DbPool>,
+    redis: Data<Client>,
+    lifetime: Data<SessionLifetime>,
+    session_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let session_id = session_id.into_inner();
+    session_store::require_owner(&mut conn, &server.id, &session_id)?;
+
+    let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+    session_store::abandon_session(&mut conn, &mut con, &lifetime, &session_id).await?;
+
+    Ok(HttpResponse::Ok().body("Session removed successfully"))
+}
+
+pub async fn validate_achievement(user_data: Json<AchievementValidation>, pool:Data<DbPool>) -> Result<HttpResponse, ApiError> {
+    // Extract user data from request
+    let user_data = user_data.into_inner();
+
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Check if user exists
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&user_data.username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    let user_achievement = UserAchievement {
+        user_id,
+        achievement_id: user_data.achievement_id,
+    };
+
+    diesel::insert_into(user_achievements::table)
+        .values(&user_achievement)
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Achievement validated successfully"))
 }
 
 //Get an achievement from the database by id (id in the URL)
 pub async fn get_achievement_by_id(
-    req: HttpRequest,
     pool: Data<DbPool>,
     achievement_id: web::Path<Uuid>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve achievement from database
-            let achievement: Achievement = match achievements::table
-                .filter(achievements::id.eq(achievement_id.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(achievement) => achievement,
-                Err(_) => {
-                    return HttpResponse::NotFound().body("Achievement not found");
-                }
-            };
-
-            HttpResponse::Ok().json(achievement)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve achievement from database
+    let achievement: Achievement = achievements::table
+        .filter(achievements::id.eq(achievement_id.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Achievement))?;
+
+    Ok(HttpResponse::Ok().json(achievement))
 }
 
 //Get all the achievements from the database
 pub async fn get_all_achievements(
-    req: HttpRequest,
     pool: Data<DbPool>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "all".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve all achievements from database
-            let achievements: Vec<Achievement> = achievements::table
-                .load(&mut conn)
-                .expect("Error loading achievements");
-            
-            let response = AchievementsResponse {achievements};
-            HttpResponse::Ok().json(response)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve all achievements from database
+    let achievements: Vec<Achievement> = achievements::table
+        .load(&mut conn)?;
+
+    let response = AchievementsResponse {achievements};
+    Ok(HttpResponse::Ok().json(response))
 }
 
 //Get all the validate achievements from the database by user id
 pub async fn get_user_achievements(
-    req: HttpRequest,
     pool: Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(username_into.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            // Retrieve all achievements from database
-            let achievements: Vec<Achievement> = user_achievements::table
-                .inner_join(achievements::table)
-                .select(achievements::all_columns)
-                .filter(user_achievements::user_id.eq(&user_id))
-                .load(&mut conn)
-                .expect("Error loading achievements");
-
-            let response = AchievementsResponse {achievements};
-            HttpResponse::Ok().json(response)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Retrieve all achievements from database
+    let achievements: Vec<Achievement> = user_achievements::table
+        .inner_join(achievements::table)
+        .select(achievements::all_columns)
+        .filter(user_achievements::user_id.eq(&user_id))
+        .load(&mut conn)?;
+
+    let response = AchievementsResponse {achievements};
+    Ok(HttpResponse::Ok().json(response))
 }
 
 //update kda of a user by user id
 pub async fn update_kda(
-    req: HttpRequest,
     pool: Data<DbPool>,
     user_data: Json<KdaUpdate>
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            let user_data = user_data.into_inner();
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&user_data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            // Update user KDA
-             match diesel::update(users::table.filter(users::id.eq(user_id)))
-                .set(users::kda.eq(user_data.new_kda))
-                .execute(&mut conn)
-             {
-                Ok(_) => {
-                    HttpResponse::Ok().body("KDA updated successfully")
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error updating user KDA");
-                }
-             }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    let user_data = user_data.into_inner();
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&user_data.username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Add to the counters of the user, the kda is computed from them
+    conn.transaction::<_, ApiError, _>(|conn| {
+        results::add_kda(conn, &user_id, user_data.kills, user_data.deaths, user_data.assists)
+    })?;
+
+    Ok(HttpResponse::Ok().body("KDA updated successfully"))
 }
 
 //Get kda of a user by username
 pub async fn get_kda(
-    req: HttpRequest,
     pool: Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "all".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve user from database
-            let user: User = match users::table
-                .filter(users::username.eq(&username_into.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(user) => user,
-                Err(_) => {
-                    return HttpResponse::NotFound().body("User not found");
-                }
-            };
-
-            HttpResponse::Ok().json(user.kda)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve user from database
+    let user: User = users::table
+        .filter(users::username.eq(&username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    Ok(HttpResponse::Ok().json(user.kda))
 }
 
 //Get all ranks from the database
 pub async fn get_all_ranks(
-    req: HttpRequest,
     pool: Data<DbPool>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "all".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve all ranks from database
-            let ranks: Vec<Rank> = ranks::table
-                .load(&mut conn)
-                .expect("Error loading ranks");
-            
-            let response = RanksResponse{ranks};
-
-            HttpResponse::Ok().json(response)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve all ranks from database, the lowest first
+    let ranks = ranks::ordered(&mut conn)?;
+
+    let response = RanksResponse{ranks};
+
+    Ok(HttpResponse::Ok().json(response))
 }
 
 pub async fn get_rank(
-    req: HttpRequest,
     pool: web::Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    // Validate the JWT token
-    let token_validation = validate_token(req, "all".to_string());
-
-    // Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve the user's rank
-            match users::table
-                .select(users::rank_id)
-                .filter(users::username.eq(&username_into.into_inner()))
-                .first::<Uuid>(&mut conn)
-            {
-                Ok(rank_id) => {
-                    // Retrieve the rank name
-                    match ranks::table
-                        .select(ranks::name)
-                        .filter(ranks::id.eq(rank_id))
-                        .first::<String>(&mut conn)
-                    {
-                        Ok(rank_name) => {
-                            HttpResponse::Ok().body(rank_name)
-                        }
-                        Err(_) => {
-                            HttpResponse::InternalServerError().body("Failed to retrieve rank name")
-                        }
-                    }
-                }
-                Err(_) => {
-                    HttpResponse::BadRequest().body("Invalid username")
-                }
-            }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve the user's rank
+    let rank_id: Uuid = users::table
+        .select(users::rank_id)
+        .filter(users::username.eq(&username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Retrieve the rank name, with its division
+    let rank = ranks::find(&mut conn, &rank_id)?;
+
+    Ok(HttpResponse::Ok().body(rank.full_name()))
 }
 
 //Update user rank by user id
 pub async fn update_rank(
-    req: HttpRequest,
     pool: Data<DbPool>,
     user_data: Json<RankUpdate>
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            let user_data = user_data.into_inner();
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&user_data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            // Update user rank
-            match diesel::update(users::table.filter(users::id.eq(user_id)))
-                .set(users::rank_id.eq(user_data.new_rank_id))
-                .execute(&mut conn)
-            {
-                Ok(_) => {
-                    HttpResponse::Ok().body("Rank updated successfully")
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error updating user rank");
-                }
-            }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    let user_data = user_data.into_inner();
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&user_data.username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Update user rank
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set(users::rank_id.eq(user_data.new_rank_id))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Rank updated successfully"))
 }
 
 //Get the number of games played by a user by username
 pub async fn get_games_played(
-    req: HttpRequest,
     pool: Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "all".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve user from database
-            let user: User = match users::table
-                .filter(users::username.eq(&username_into.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(user) => user,
-                Err(_) => {
-                    return HttpResponse::NotFound().body("User not found");
-                }
-            };
-
-            HttpResponse::Ok().json(user.nb_games)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve user from database
+    let user: User = users::table
+        .filter(users::username.eq(&username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    Ok(HttpResponse::Ok().json(user.nb_games))
 }
 
 //Update the number of game played by a user by username
 pub async fn update_games_played(
-    req: HttpRequest,
     pool: Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            // Retrieve user from database
-            let user: User = match users::table
-                .filter(users::username.eq(&username_into.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(user) => user,
-                Err(_) => {
-                    return HttpResponse::NotFound().body("User not found");
-                }
-            };
-
-            // Update user games played
-            match diesel::update(users::table.filter(users::id.eq(user.id)))
-                .set(users::nb_games.eq(user.nb_games + 1))
-                .execute(&mut conn)
-            {
-                Ok(_) => {
-                    HttpResponse::Ok().body("Games played updated successfully")
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error updating user games played");
-                }
-            }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    // Retrieve user from database
+    let user: User = users::table
+        .filter(users::username.eq(&username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Update user games played
+    diesel::update(users::table.filter(users::id.eq(user.id)))
+        .set(users::nb_games.eq(user.nb_games + 1))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Games played updated successfully"))
 }
 
 pub  async fn get_ip(
     req : HttpRequest,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req.clone(), "server".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Get the client's IP address
-            let client_ip = req.connection_info().peer_addr().unwrap().to_string();
-
-            // Return the client's IP address
-            HttpResponse::Ok().json(client_ip)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Get the client's IP address
+    let client_ip = req
+        .connection_info()
+        .peer_addr()
+        .ok_or(ApiError::Internal("No peer address for the request".to_string()))?
+        .to_string();
+
+    // Return the client's IP address
+    Ok(HttpResponse::Ok().json(client_ip))
 }
 
 //Send a friend request to a user by username
 pub async fn send_friend_request(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     pool: Data<DbPool>,
     user_data: Json<FriendRequest>
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let data = user_data.into_inner();
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            let friend_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&data.friend_username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid friend username");
-                }
-            };
-
-            //Insert the friend request into the database
-            match diesel::insert_into(friend_requests::table)
-                .values((friend_requests::user_id.eq(user_id), friend_requests::friend_id.eq(friend_id)))
-                .execute(&mut conn)
-            {
-                Ok(_) => {
-                    HttpResponse::Ok().body("Friend request sent successfully")
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Error inserting friend request into database");
-                }
-            }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let data = user_data.into_inner();
+    let user_id: Uuid = acting_user_id(&user, data.username.as_deref(), &mut conn)?;
+
+    let friend_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&data.friend_username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Friend))?;
+
+    //Insert the friend request into the database
+    diesel::insert_into(friend_requests::table)
+        .values((friend_requests::user_id.eq(user_id), friend_requests::friend_id.eq(friend_id)))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Friend request sent successfully"))
 }
 
 //Accept a friend request by username
 pub async fn accept_friend_request(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     pool: Data<DbPool>,
     user_data: Json<FriendRequest>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let data = user_data.into_inner();
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            let friend_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&data.friend_username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid friend username");
-                }
-            };
-
-                //Remove the friend request
-                match diesel::delete(friend_requests::table
-                    .filter(friend_requests::user_id.eq(friend_id))
-                    .filter(friend_requests::friend_id.eq(user_id)))
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {
-                    }
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error removing friend request");
-                    }
-                }
-                //Add the friend
-                match diesel::insert_into(friends::table)
-                    .values((friends::user_id.eq(user_id), friends::friend_id.eq(friend_id)))
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {
-                    }
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error inserting friend into database");
-                    }
-                }
-
-                //Add the friend
-                match diesel::insert_into(friends::table)
-                    .values((friends::user_id.eq(friend_id), friends::friend_id.eq(user_id)))
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {
-                        HttpResponse::Ok().body("Friend request accepted successfully")
-                    }
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error inserting friend into database");
-                    }
-                }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let data = user_data.into_inner();
+    let user_id: Uuid = acting_user_id(&user, data.username.as_deref(), &mut conn)?;
+
+    let friend_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&data.friend_username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Friend))?;
+
+    conn.transaction::<_, ApiError, _>(|conn| {
+        //Remove the friend request, only a request the friend sent can be accepted
+        let removed = diesel::delete(friend_requests::table
+            .filter(friend_requests::user_id.eq(friend_id))
+            .filter(friend_requests::friend_id.eq(user_id)))
+            .execute(conn)?;
+        if removed != 1 {
+            return Err(ApiError::NotFound(Resource::FriendRequest));
         }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+
+        //Add the friend
+        diesel::insert_into(friends::table)
+            .values((friends::user_id.eq(user_id), friends::friend_id.eq(friend_id)))
+            .execute(conn)?;
+
+        //Add the friend
+        diesel::insert_into(friends::table)
+            .values((friends::user_id.eq(friend_id), friends::friend_id.eq(user_id)))
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    Ok(HttpResponse::Ok().body("Friend request accepted successfully"))
 }
 
 //Get all the friends of a user by username
 pub async fn get_friends(
-    req: HttpRequest,
     pool: Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(username_into.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            // Retrieve all friends from database
-            let friends: Vec<FriendData> = friends::table
-                .inner_join(users::table)
-                .select((users::username, users::kda, users::nb_games,users::rank_id))
-                .filter(friends::user_id.eq(&user_id))
-                .load(&mut conn)
-                .expect("Error loading friends");
-
-            let friends_response = FriendsResponse{friends};
-            HttpResponse::Ok().json(friends_response)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Retrieve all friends from database
+    let friends: Vec<FriendData> = friends::table
+        .inner_join(users::table)
+        .select((users::username, users::kda, users::nb_games,users::rank_id))
+        .filter(friends::user_id.eq(&user_id))
+        .load(&mut conn)?;
+
+    let friends_response = FriendsResponse{friends};
+    Ok(HttpResponse::Ok().json(friends_response))
 }
 
 //Get all the pending friend requests of a user by username
 pub async fn get_pending_friend_requests(
-    req: HttpRequest,
     pool: Data<DbPool>,
     username_into: web::Path<String>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(username_into.into_inner()))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            // Retrieve all friends from database
-            let friends: Vec<FriendData> = friend_requests::table
-                .inner_join(users::table)
-                .select((users::username, users::kda, users::nb_games,users::rank_id))
-                .filter(friend_requests::friend_id.eq(&user_id))
-                .load(&mut conn)
-                .expect("Error loading friends");
-
-            let friends_response = FriendsResponse{friends};
-            HttpResponse::Ok().json(friends_response)
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
-    }
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(username_into.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    // Retrieve all friends from database
+    let friends: Vec<FriendData> = friend_requests::table
+        .inner_join(users::table)
+        .select((users::username, users::kda, users::nb_games,users::rank_id))
+        .filter(friend_requests::friend_id.eq(&user_id))
+        .load(&mut conn)?;
+
+    let friends_response = FriendsResponse{friends};
+    Ok(HttpResponse::Ok().json(friends_response))
 }
 
 //Remove a friend
 pub async fn remove_friend(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     pool: Data<DbPool>,
     user_data: Json<FriendRequest>,
-) -> HttpResponse {
-    //Validate the JWT token
-    let token_validation = validate_token(req, "client".to_string());
-    //Switch on the token validation result
-    match token_validation {
-        0 => {
-            // Establish a database connection
-            let mut conn = pool.get().expect("Couldn't get db connection from pool");
-
-            let data = user_data.into_inner();
-            let user_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&data.username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid username");
-                }
-            };
-
-            let friend_id: Uuid = match users::table
-                .select(users::id)
-                .filter(users::username.eq(&data.friend_username))
-                .first(&mut conn)
-            {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid friend username");
-                }
-            };
-
-                //Remove the friend
-                match diesel::delete(friends::table
-                    .filter(friends::user_id.eq(user_id))
-                    .filter(friends::friend_id.eq(friend_id)))
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {
-                    }
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error removing friend");
-                    }
-                }
-
-                //Remove the friend
-                match diesel::delete(friends::table
-                    .filter(friends::user_id.eq(friend_id))
-                    .filter(friends::friend_id.eq(user_id)))
-                    .execute(&mut conn)
-                {
-                    Ok(_) => {
-                        HttpResponse::Ok().body("Friend removed successfully")
-                    }
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body("Error removing friend");
-                    }
-                }
-        }
-        1 => HttpResponse::Unauthorized().body("Unauthorized"),
-        2 => HttpResponse::Forbidden().body("Permission denied"),
-        _ => HttpResponse::InternalServerError().body("Internal Server Error"),
+) -> Result<HttpResponse, ApiError> {
+    // Establish a database connection
+    let mut conn = pool.get()?;
+
+    let data = user_data.into_inner();
+    let user_id: Uuid = acting_user_id(&user, data.username.as_deref(), &mut conn)?;
+
+    let friend_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(&data.friend_username))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Friend))?;
+
+    //Remove the friend
+    diesel::delete(friends::table
+        .filter(friends::user_id.eq(user_id))
+        .filter(friends::friend_id.eq(friend_id)))
+        .execute(&mut conn)?;
+
+    //Remove the friend
+    diesel::delete(friends::table
+        .filter(friends::user_id.eq(friend_id))
+        .filter(friends::friend_id.eq(user_id)))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().body("Friend removed successfully"))
+}
+
+//Accounts with an unverified email can't play ranked game modes
+fn require_verified_email(conn: &mut PgConnection, user_id: &Uuid) -> Result<(), ApiError> {
+    let email_verified: bool = users::table
+        .select(users::email_verified)
+        .filter(users::id.eq(user_id))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+    if !email_verified {
+        return Err(ApiError::EmailNotVerified);
     }
+    Ok(())
 }
 
-fn validate_token(req: HttpRequest, role_value : String) -> i32 {
-    let token =  match req
-        .headers()
-        .get(actix_web::http::header::AUTHORIZATION)
-        {
-            Some(token) => token.to_str().unwrap().to_string(),
-            None => {
-                return 1;
-            }
-        };
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret("secret".as_ref()),
-        &Validation::new(Algorithm::HS256),
-    );
-
-    return match token_data {
-        Ok(claims) => {
-            if claims.claims.role == role_value || role_value == "all"{
-                0
-            } else {
-                2
-            }
-        }
-        Err(_) => 1,
+//Get the id of the user a request acts on
+//Clients always act as themselves, only server and admin tokens can name another user
+fn acting_user_id(user: &AuthenticatedUser, username: Option<&str>, conn: &mut PgConnection) -> Result<Uuid, ApiError> {
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(user.id),
+    };
+
+    let user_id: Uuid = users::table
+        .select(users::id)
+        .filter(users::username.eq(username))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    if user_id != user.id && !user.is_privileged() {
+        return Err(ApiError::ActingForOtherUser);
     }
+    Ok(user_id)
 }
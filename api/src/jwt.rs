@@ -0,0 +1,160 @@
+// jwt.rs
+use std::collections::{HashMap, HashSet};
+use std::{env, fmt, fs, io};
+use std::path::Path;
+
+use jsonwebtoken::{Algorithm, decode, decode_header, DecodingKey, encode, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: String,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    MissingKeyId,
+    UnknownKey(String),
+    RetiredKey(String),
+    Invalid(jsonwebtoken::errors::Error),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::MissingKeyId => write!(f, "Token has no key id"),
+            TokenError::UnknownKey(kid) => write!(f, "Token signed with unknown key {}", kid),
+            TokenError::RetiredKey(kid) => write!(f, "Token signed with retired key {}", kid),
+            TokenError::Invalid(e) => write!(f, "Invalid token : {}", e),
+        }
+    }
+}
+
+// Secret of the development examples, refused so it can't end up signing real tokens
+const PLACEHOLDER_SECRET: &str = "change-this-secret-outside-of-development";
+
+struct SigningKey {
+    algorithm: Algorithm,
+    // Keys only kept for verification during a rotation have no private part
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+}
+
+// Keys used to sign and verify the tokens, indexed by their key id (kid)
+//
+// Configured through the environment :
+// - JWT_ACTIVE_KID : kid of the key used to sign new tokens
+// - JWT_KEYS : `;` separated list of `kid:algorithm:material`
+//   - HS256 : the material is the shared secret
+//   - RS256 / EdDSA : the material is a directory holding `public.pem` and, for the signing key, `private.pem`
+// - JWT_RETIRED_KIDS : `,` separated list of kids that must no longer be accepted
+pub struct JwtKeys {
+    active_kid: String,
+    keys: HashMap<String, SigningKey>,
+    retired: HashSet<String>,
+}
+
+impl JwtKeys {
+    pub fn from_env() -> io::Result<JwtKeys> {
+        let active_kid = env::var("JWT_ACTIVE_KID").map_err(|_| io::Error::other("JWT_ACTIVE_KID must be set"))?;
+        let keys_config = env::var("JWT_KEYS").map_err(|_| io::Error::other("JWT_KEYS must be set"))?;
+        let retired: HashSet<String> = env::var("JWT_RETIRED_KIDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|kid| kid.trim().to_string())
+            .filter(|kid| !kid.is_empty())
+            .collect();
+
+        if keys_config.split(';').all(|entry| entry.trim().is_empty()) {
+            return Err(io::Error::other("JWT_KEYS must configure at least one key"));
+        }
+
+        let mut keys = HashMap::new();
+        for entry in keys_config.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let mut parts = entry.splitn(3, ':');
+            let (kid, algorithm, material) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(kid), Some(algorithm), Some(material)) => (kid.to_string(), algorithm, material),
+                _ => return Err(io::Error::other(format!("Invalid JWT_KEYS entry : {}", entry))),
+            };
+            if retired.contains(&kid) {
+                continue;
+            }
+            let key = load_key(algorithm, material)
+                .map_err(|e| io::Error::other(format!("Invalid key {} : {}", kid, e)))?;
+            keys.insert(kid, key);
+        }
+
+        match keys.get(&active_kid) {
+            Some(key) if key.encoding.is_some() => {}
+            Some(_) => return Err(io::Error::other(format!("Active key {} has no private key", active_kid))),
+            None => return Err(io::Error::other(format!("Active key {} is not configured", active_kid))),
+        }
+
+        Ok(JwtKeys { active_kid, keys, retired })
+    }
+
+    pub fn sign(&self, claims: &Claims) -> Result<String, jsonwebtoken::errors::Error> {
+        let key = &self.keys[&self.active_kid];
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(self.active_kid.clone());
+        // The active key is checked for a private part when the keys are loaded
+        encode(&header, claims, key.encoding.as_ref().expect("Active key without private key"))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let header = decode_header(token).map_err(TokenError::Invalid)?;
+        let kid = header.kid.ok_or(TokenError::MissingKeyId)?;
+        if self.retired.contains(&kid) {
+            return Err(TokenError::RetiredKey(kid));
+        }
+        let key = self.keys.get(&kid).ok_or(TokenError::UnknownKey(kid))?;
+
+        decode::<Claims>(token, &key.decoding, &Validation::new(key.algorithm))
+            .map(|data| data.claims)
+            .map_err(TokenError::Invalid)
+    }
+}
+
+fn load_key(algorithm: &str, material: &str) -> Result<SigningKey, String> {
+    match algorithm {
+        "HS256" if material.is_empty() => Err("Empty HS256 secret".to_string()),
+        "HS256" if material == PLACEHOLDER_SECRET => Err("The example HS256 secret must be replaced".to_string()),
+        "HS256" => Ok(SigningKey {
+            algorithm: Algorithm::HS256,
+            encoding: Some(EncodingKey::from_secret(material.as_bytes())),
+            decoding: DecodingKey::from_secret(material.as_bytes()),
+        }),
+        "RS256" => {
+            let (private_pem, public_pem) = read_pem_pair(material)?;
+            Ok(SigningKey {
+                algorithm: Algorithm::RS256,
+                encoding: private_pem.map(|pem| EncodingKey::from_rsa_pem(&pem)).transpose().map_err(|e| e.to_string())?,
+                decoding: DecodingKey::from_rsa_pem(&public_pem).map_err(|e| e.to_string())?,
+            })
+        }
+        "EdDSA" => {
+            let (private_pem, public_pem) = read_pem_pair(material)?;
+            Ok(SigningKey {
+                algorithm: Algorithm::EdDSA,
+                encoding: private_pem.map(|pem| EncodingKey::from_ed_pem(&pem)).transpose().map_err(|e| e.to_string())?,
+                decoding: DecodingKey::from_ed_pem(&public_pem).map_err(|e| e.to_string())?,
+            })
+        }
+        _ => Err(format!("Unsupported algorithm {}", algorithm)),
+    }
+}
+
+fn read_pem_pair(directory: &str) -> Result<(Option<Vec<u8>>, Vec<u8>), String> {
+    let directory = Path::new(directory);
+    let public_pem = fs::read(directory.join("public.pem")).map_err(|e| e.to_string())?;
+    let private_pem = match fs::read(directory.join("private.pem")) {
+        Ok(pem) => Some(pem),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok((private_pem, public_pem))
+}
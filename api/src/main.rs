@@ -1,17 +1,51 @@
 // main.rs
 use actix_web::{web, App, HttpServer};
 use std::{env, io};
+use std::time::Duration;
 use actix_web::web::Data;
 use diesel::pg::PgConnection;
 use diesel::r2d2::{self, ConnectionManager};
 use dotenvy::dotenv;
 
+use crate::auth::{ProvisioningToken, RequireRole};
+use crate::errors::ApiError;
+use crate::rate_limit::{LoginThrottle, RateLimit};
+
+mod auth;
+mod config;
+mod errors;
 mod handlers;
+mod jwt;
+mod mailer;
+mod matchmaking;
 mod models;
+mod passwords;
+mod queue;
+mod ranks;
+mod rate_limit;
+mod rating;
+mod results;
 mod schema;
+mod session_store;
+mod tokens;
+mod validation;
 
 type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+const CLIENT: &[&str] = &["client"];
+const SERVER: &[&str] = &["server"];
+const ADMIN: &[&str] = &["admin"];
+const ALL: &[&str] = &["client", "server", "admin"];
+
+// Requests allowed per IP on a route : name of the counter, requests, window in seconds
+const REGISTER_LIMIT: RateLimit = RateLimit::new("register", 5, 3600);
+const LOGIN_LIMIT: RateLimit = RateLimit::new("login", 30, 60);
+const TOKEN_LIMIT: RateLimit = RateLimit::new("token", 30, 60);
+const MAIL_LIMIT: RateLimit = RateLimit::new("mail", 5, 3600);
+const ACCOUNT_TOKEN_LIMIT: RateLimit = RateLimit::new("account_token", 10, 600);
+const MATCHMAKING_LIMIT: RateLimit = RateLimit::new("matchmaking", 30, 60);
+const QUEUE_POLL_LIMIT: RateLimit = RateLimit::new("queue_poll", 120, 60);
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
@@ -26,37 +60,103 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to create pool.");
 
     let redis_url = env::var("REDIS_URL").expect("REDIS_URL must be set");
-    let client = redis::Client::open(redis_url).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let client = redis::Client::open(redis_url).map_err(io::Error::other)?;
     let redis_data = web::Data::new(client);
 
+    let jwt_keys = web::Data::new(jwt::JwtKeys::from_env()?);
+    let registration_policy = web::Data::new(validation::RegistrationPolicy::from_env()?);
+    let provisioning_token = web::Data::new(ProvisioningToken::from_env());
+    let login_throttle = web::Data::new(LoginThrottle::from_env()?);
+    let passwords = web::Data::new(passwords::Passwords::from_env()?);
+
+    //First admin account, created from the environment so `/admin/users` can be reached without editing the database
+    let admin_username: String = config::env_or("ADMIN_USERNAME", String::new())?;
+    if !admin_username.is_empty() {
+        let admin = models::NewUser {
+            username: admin_username,
+            email: config::env_or("ADMIN_EMAIL", String::new())?,
+            password: config::env_or("ADMIN_PASSWORD", String::new())?,
+        };
+        let mut conn = pool.get().map_err(io::Error::other)?;
+        if handlers::bootstrap_admin(&mut conn, &passwords, &registration_policy, admin)
+            .map_err(|e| io::Error::other(format!("Failed to create the first admin : {:?}", e)))?
+        {
+            log::info!("First admin account created");
+        }
+    }
+
+    let matchmaking_rules = web::Data::new(matchmaking::MatchmakingRules::from_env()?);
+    let matchmaking_interval = Duration::from_millis(config::env_or("MATCHMAKING_INTERVAL_MS", 1000)?);
+    queue::spawn_worker(pool.clone(), redis_data.clone(), matchmaking_rules.clone(), matchmaking_interval);
+    let session_lifetime = web::Data::new(session_store::SessionLifetime::from_env()?);
+    session_store::spawn_reaper(pool.clone(), redis_data.clone(), session_lifetime.clone());
+    let rating_system = web::Data::new(rating::RatingSystem::from_env()?);
+    let mailer: web::Data<dyn mailer::Mailer> = web::Data::from(mailer::from_env()?);
+
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(pool.clone()))
             .app_data(redis_data.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(registration_policy.clone())
+            .app_data(provisioning_token.clone())
+            .app_data(mailer.clone())
+            .app_data(login_throttle.clone())
+            .app_data(passwords.clone())
+            .app_data(matchmaking_rules.clone())
+            .app_data(session_lifetime.clone())
+            .app_data(rating_system.clone())
+            .app_data(web::JsonConfig::default().error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()))
+            .app_data(web::PathConfig::default().error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()))
             .route("/hello", web::get().to(handlers::hello))
-            .route("/register", web::post().to(handlers::register_user))
-            .route("/login", web::post().to(handlers::login_user))
-            .route("/session/{other_username}", web::get().to(handlers::request_session))
-            .route("/session", web::post().to(handlers::register_session))
-            .route("/session/{session_id}", web::delete().to(handlers::remove_session))
-            .route("/connect", web::post().to(handlers::connect_to_session))
-            .route("/achievement", web::post().to(handlers::validate_achievement))
-            .route("/achievement/{achievement_id}", web::get().to(handlers::get_achievement_by_id))
-            .route("/achievements", web::get().to(handlers::get_all_achievements))
-            .route("/user_achievements/{username_into}", web::get().to(handlers::get_user_achievements))
-            .route("/kda", web::put().to(handlers::update_kda))
-            .route("/kda/{username_into}", web::get().to(handlers::get_kda))
-            .route("/ranks", web::get().to(handlers::get_all_ranks))
-            .route("/rank", web::put().to(handlers::update_rank))
-            .route("/rank/{username_into}",web::get().to(handlers::get_rank))
-            .route("/nb_games/{username_into}", web::put().to(handlers::update_games_played))
-            .route("/nb_games/{username_into}", web::get().to(handlers::get_games_played))
-            .route("/get_ip",web::get().to(handlers::get_ip))
-            .route("/send_friend_request",web::post().to(handlers::send_friend_request))
-            .route("/accept_friend_request",web::post().to(handlers::accept_friend_request))
-            .route("/get_friends/{username_into}",web::get().to(handlers::get_friends))
-            .route("/get_friend_requests/{username_into}",web::get().to(handlers::get_pending_friend_requests))
-            .route("/friend",web::delete().to(handlers::remove_friend))
+            .route("/register", web::post().to(handlers::register_user).wrap(REGISTER_LIMIT))
+            .route("/register/server", web::post().to(handlers::provision_server).wrap(REGISTER_LIMIT))
+            .route("/admin/users", web::post().to(handlers::create_privileged_user).wrap(RequireRole::new(ADMIN)))
+            .route("/admin/users/{user_id}/revoke", web::post().to(handlers::revoke_user_tokens).wrap(RequireRole::new(ADMIN)))
+            .route("/login", web::post().to(handlers::login_user).wrap(LOGIN_LIMIT))
+            .route("/email/verify", web::post().to(handlers::verify_email).wrap(ACCOUNT_TOKEN_LIMIT))
+            .route("/email/verification", web::post().to(handlers::resend_verification_email).wrap(RequireRole::new(ALL)).wrap(MAIL_LIMIT))
+            .route("/password/forgot", web::post().to(handlers::forgot_password).wrap(MAIL_LIMIT))
+            .route("/password/reset", web::post().to(handlers::reset_password).wrap(ACCOUNT_TOKEN_LIMIT))
+            .route("/token/refresh", web::post().to(handlers::refresh_token).wrap(TOKEN_LIMIT))
+            .route("/logout", web::post().to(handlers::logout))
+            .route("/matchmaking/queue", web::post().to(handlers::join_queue).wrap(RequireRole::new(CLIENT)).wrap(MATCHMAKING_LIMIT))
+            .route("/matchmaking/queue", web::get().to(handlers::get_queue_status).wrap(RequireRole::new(CLIENT)).wrap(QUEUE_POLL_LIMIT))
+            .route("/matchmaking/queue", web::delete().to(handlers::leave_queue).wrap(RequireRole::new(CLIENT)))
+            .route("/matchmaking/party/invite", web::post().to(handlers::invite_to_party).wrap(RequireRole::new(CLIENT)))
+            .route("/matchmaking/party/accept", web::post().to(handlers::accept_party_invite).wrap(RequireRole::new(CLIENT)))
+            .route("/matchmaking/party", web::delete().to(handlers::leave_party).wrap(RequireRole::new(CLIENT)))
+            .route("/preferred_region", web::put().to(handlers::update_preferred_region).wrap(RequireRole::new(CLIENT)))
+            .route("/session", web::post().to(handlers::register_session).wrap(RequireRole::new(SERVER)))
+            .route("/session/{session_id}/heartbeat", web::post().to(handlers::session_heartbeat).wrap(RequireRole::new(SERVER)))
+            .route("/session/{session_id}/leave", web::post().to(handlers::leave_session).wrap(RequireRole::new(ALL)))
+            .route("/session/{session_id}/result", web::post().to(handlers::submit_match_result).wrap(RequireRole::new(SERVER)))
+            .route("/session/{session_id}/teams", web::get().to(handlers::get_session_teams).wrap(RequireRole::new(SERVER)))
+            .route("/session/{session_id}", web::patch().to(handlers::update_session).wrap(RequireRole::new(SERVER)))
+            .route("/session/{session_id}", web::delete().to(handlers::remove_session).wrap(RequireRole::new(SERVER)))
+            .route("/game_modes", web::get().to(handlers::get_game_modes).wrap(RequireRole::new(ALL)))
+            .route("/connect", web::post().to(handlers::connect_to_session).wrap(RequireRole::new(ALL)))
+            .route("/connect/friend", web::post().to(handlers::join_friend).wrap(RequireRole::new(ALL)))
+            .route("/players/{username}/matches", web::get().to(handlers::get_player_matches).wrap(RequireRole::new(ALL)))
+            .route("/matches/{match_id}", web::get().to(handlers::get_match).wrap(RequireRole::new(ALL)))
+            .route("/achievement", web::post().to(handlers::validate_achievement).wrap(RequireRole::new(SERVER)))
+            .route("/achievement/{achievement_id}", web::get().to(handlers::get_achievement_by_id).wrap(RequireRole::new(CLIENT)))
+            .route("/achievements", web::get().to(handlers::get_all_achievements).wrap(RequireRole::new(ALL)))
+            .route("/user_achievements/{username_into}", web::get().to(handlers::get_user_achievements).wrap(RequireRole::new(CLIENT)))
+            .route("/kda", web::put().to(handlers::update_kda).wrap(RequireRole::new(SERVER)))
+            .route("/kda/{username_into}", web::get().to(handlers::get_kda).wrap(RequireRole::new(ALL)))
+            .route("/ranks", web::get().to(handlers::get_all_ranks).wrap(RequireRole::new(ALL)))
+            .route("/rank", web::put().to(handlers::update_rank).wrap(RequireRole::new(SERVER)))
+            .route("/rank/{username_into}",web::get().to(handlers::get_rank).wrap(RequireRole::new(ALL)))
+            .route("/nb_games/{username_into}", web::put().to(handlers::update_games_played).wrap(RequireRole::new(SERVER)))
+            .route("/nb_games/{username_into}", web::get().to(handlers::get_games_played).wrap(RequireRole::new(ALL)))
+            .route("/get_ip",web::get().to(handlers::get_ip).wrap(RequireRole::new(SERVER)))
+            .route("/send_friend_request",web::post().to(handlers::send_friend_request).wrap(RequireRole::new(ALL)))
+            .route("/accept_friend_request",web::post().to(handlers::accept_friend_request).wrap(RequireRole::new(ALL)))
+            .route("/get_friends/{username_into}",web::get().to(handlers::get_friends).wrap(RequireRole::new(CLIENT)))
+            .route("/get_friend_requests/{username_into}",web::get().to(handlers::get_pending_friend_requests).wrap(RequireRole::new(CLIENT)))
+            .route("/friend",web::delete().to(handlers::remove_friend).wrap(RequireRole::new(ALL)))
     })
         .bind("0.0.0.0:8000")?
         .run()
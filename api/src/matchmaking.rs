@@ -0,0 +1,299 @@
+// matchmaking.rs
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::env_or;
+
+// Rules deciding which queued players go into which session, kept free of Redis and database access
+//
+// Configured through the environment, gaps are in kda or in rating points depending on the skill measure :
+// - MATCHMAKING_SKILL : `kda` or `rating`, what the skill of the players is measured with (default `kda`)
+// - MATCHMAKING_BASE_WINDOW : largest skill gap accepted as soon as a player enqueues (default 0.5, 100 with `rating`)
+// - MATCHMAKING_WINDOW_GROWTH : how much the accepted gap grows per second of waiting (default 0.05, 5 with `rating`)
+// - MATCHMAKING_MAX_WINDOW : largest skill gap ever accepted (default 5.0, 500 with `rating`)
+// - MATCHMAKING_RANK_WEIGHT : skill gap added by each rank tier between a ticket and a session, divisions being tiers (default 0.2, 0 with `rating`)
+// - MATCHMAKING_MAX_PING : highest ping in milliseconds to the region of a session before it counts as distant (default 80)
+// - MATCHMAKING_REGION_WAIT : seconds of waiting after which sessions in distant regions are accepted (default 30)
+#[derive(Debug, Clone)]
+pub struct MatchmakingRules {
+    pub skill: SkillMeasure,
+    pub base_window: f32,
+    pub window_growth: f32,
+    pub max_window: f32,
+    pub rank_weight: f32,
+    pub max_ping: u32,
+    pub region_wait: i64,
+}
+
+// A player, or a group of players joining together, waiting for a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticket {
+    pub id: Uuid,
+    pub players: Vec<Uuid>,
+    pub game_mode: String,
+    pub skill: f32,
+    // Average tier of the ranks of the players
+    #[serde(default)]
+    pub tier: f32,
+    // Ping of the players to each region, or the region they want to play in
+    #[serde(default)]
+    pub pings: HashMap<String, u32>,
+    #[serde(default)]
+    pub region: Option<String>,
+    pub enqueued_at: i64,
+}
+
+// A session as seen by the matchmaker, `skill` and `tier` are None while nobody is in it
+// and `team_room` is the most players a party can have to fit in one of its teams
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub id: Uuid,
+    pub game_mode: String,
+    pub capacity: usize,
+    pub players: usize,
+    pub team_room: usize,
+    pub skill: Option<f32>,
+    pub tier: Option<f32>,
+    pub region: Option<String>,
+}
+
+// What the skill of tickets and sessions is, also used to balance the teams of a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkillMeasure {
+    Kda,
+    // Hidden skill rating of the players, see rating.rs
+    Rating,
+}
+
+impl FromStr for SkillMeasure {
+    type Err = String;
+
+    fn from_str(measure: &str) -> Result<SkillMeasure, String> {
+        match measure {
+            "kda" => Ok(SkillMeasure::Kda),
+            "rating" => Ok(SkillMeasure::Rating),
+            other => Err(format!("Unknown skill measure : {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub ticket_id: Uuid,
+    pub session_id: Uuid,
+}
+
+impl MatchmakingRules {
+    pub fn from_env() -> io::Result<MatchmakingRules> {
+        let skill = env_or("MATCHMAKING_SKILL", SkillMeasure::Kda)?;
+        //Ratings already follow the ranks and are a few hundred points apart, kdas a few units
+        let (base_window, window_growth, max_window, rank_weight) = match skill {
+            SkillMeasure::Kda => (0.5, 0.05, 5.0, 0.2),
+            SkillMeasure::Rating => (100.0, 5.0, 500.0, 0.0),
+        };
+        Ok(MatchmakingRules {
+            skill,
+            base_window: env_or("MATCHMAKING_BASE_WINDOW", base_window)?,
+            window_growth: env_or("MATCHMAKING_WINDOW_GROWTH", window_growth)?,
+            max_window: env_or("MATCHMAKING_MAX_WINDOW", max_window)?,
+            rank_weight: env_or("MATCHMAKING_RANK_WEIGHT", rank_weight)?,
+            max_ping: env_or("MATCHMAKING_MAX_PING", 80)?,
+            region_wait: env_or("MATCHMAKING_REGION_WAIT", 30)?,
+        })
+    }
+
+    //Ping of the players of a ticket to a session, 0 in the region they asked for and None when unknown
+    fn ping(&self, ticket: &Ticket, candidate: &Candidate) -> Option<u32> {
+        let region = candidate.region.as_ref()?;
+        if ticket.pings.is_empty() {
+            return (ticket.region.as_ref() == Some(region)).then_some(0);
+        }
+        ticket.pings.get(region).copied()
+    }
+
+    //Whether a session is close enough to the players of a ticket, any session is once they waited long enough
+    fn is_near(&self, ticket: &Ticket, candidate: &Candidate, waited: i64) -> bool {
+        if waited >= self.region_wait || (ticket.pings.is_empty() && ticket.region.is_none()) {
+            return true;
+        }
+        self.ping(ticket, candidate).is_some_and(|ping| ping <= self.max_ping)
+    }
+
+    //Distance between the players of a ticket and the players of a session, their skill gap plus their rank gap
+    fn gap(&self, ticket: &Ticket, skill: f32, tier: f32) -> f32 {
+        (skill - ticket.skill).abs() + self.rank_weight * (tier - ticket.tier).abs()
+    }
+
+    //Largest skill gap accepted for a ticket that has waited `waited` seconds
+    pub fn window(&self, waited: i64) -> f32 {
+        (self.base_window + self.window_growth * waited.max(0) as f32).min(self.max_window)
+    }
+
+    //Place the tickets in the sessions, the ones waiting the longest first
+    //
+    //A ticket goes to the session of its game mode with the closest skill and rank within its window that has room for all of its
+    //players on a single team.
+    //Empty sessions are only used when no session with players fits, so sessions fill up before new ones start, the one with the
+    //lowest ping first.
+    //Sessions in distant regions are ignored until the ticket has waited `region_wait` seconds.
+    pub fn assign(&self, tickets: &[Ticket], candidates: &[Candidate], now: i64) -> Vec<Assignment> {
+        let mut tickets: Vec<&Ticket> = tickets.iter().collect();
+        tickets.sort_by_key(|ticket| ticket.enqueued_at);
+        let mut candidates = candidates.to_vec();
+
+        let mut assignments = Vec::new();
+        for ticket in tickets {
+            let waited = now - ticket.enqueued_at;
+            let window = self.window(waited);
+            let size = ticket.players.len();
+
+            let fits = |candidate: &Candidate| {
+                candidate.game_mode == ticket.game_mode
+                    && candidate.players + size <= candidate.capacity
+                    && size <= candidate.team_room
+                    && self.is_near(ticket, candidate, waited)
+            };
+            let closest = candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, candidate)| fits(candidate))
+                .filter_map(|(index, candidate)| {
+                    let tier = candidate.tier.unwrap_or(ticket.tier);
+                    candidate.skill.map(|skill| (index, self.gap(ticket, skill, tier)))
+                })
+                .filter(|(_, gap)| *gap <= window)
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index);
+            let chosen = closest.or_else(|| {
+                candidates
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, candidate)| candidate.skill.is_none() && fits(candidate))
+                    .min_by_key(|(_, candidate)| self.ping(ticket, candidate).unwrap_or(u32::MAX))
+                    .map(|(index, _)| index)
+            });
+
+            if let Some(index) = chosen {
+                let candidate = &mut candidates[index];
+                let skill = candidate.skill.unwrap_or(ticket.skill);
+                let tier = candidate.tier.unwrap_or(ticket.tier);
+                let total = candidate.players + size;
+                candidate.skill = Some((skill * candidate.players as f32 + ticket.skill * size as f32) / total as f32);
+                candidate.tier = Some((tier * candidate.players as f32 + ticket.tier * size as f32) / total as f32);
+                candidate.players = total;
+                // Parties may share teams with others, the room left is only known once the session is updated
+                candidate.team_room = candidate.team_room.min(candidate.capacity - total);
+                assignments.push(Assignment {
+                    ticket_id: ticket.id,
+                    session_id: candidate.id,
+                });
+            }
+        }
+        assignments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> MatchmakingRules {
+        MatchmakingRules {
+            skill: SkillMeasure::Kda,
+            base_window: 0.5,
+            window_growth: 0.05,
+            max_window: 5.0,
+            rank_weight: 0.0,
+            max_ping: 80,
+            region_wait: 30,
+        }
+    }
+
+    fn ticket(players: usize, game_mode: &str, skill: f32, enqueued_at: i64) -> Ticket {
+        Ticket {
+            id: Uuid::new_v4(),
+            players: (0..players).map(|_| Uuid::new_v4()).collect(),
+            game_mode: game_mode.to_string(),
+            skill,
+            tier: 1.0,
+            pings: HashMap::new(),
+            region: None,
+            enqueued_at,
+        }
+    }
+
+    fn candidate(game_mode: &str, players: usize, skill: Option<f32>) -> Candidate {
+        Candidate {
+            id: Uuid::new_v4(),
+            game_mode: game_mode.to_string(),
+            capacity: 6,
+            players,
+            team_room: 3,
+            skill,
+            tier: skill.map(|_| 1.0),
+            region: None,
+        }
+    }
+
+    #[test]
+    fn window_grows_with_waiting_up_to_the_maximum() {
+        let rules = rules();
+        assert_eq!(rules.window(0), 0.5);
+        assert!((rules.window(10) - 1.0).abs() < 0.0001);
+        assert!(rules.window(20) > rules.window(10));
+        assert_eq!(rules.window(1000), 5.0);
+        assert_eq!(rules.window(-10), 0.5);
+    }
+
+    #[test]
+    fn other_game_modes_are_never_matched() {
+        let tickets = [ticket(1, "3v3", 1.0, 0)];
+        let candidates = [candidate("1v1", 1, Some(1.0)), candidate("1v1", 0, None)];
+        assert!(rules().assign(&tickets, &candidates, 10_000).is_empty());
+    }
+
+    #[test]
+    fn sessions_with_players_are_preferred_over_empty_ones() {
+        let tickets = [ticket(1, "3v3", 1.0, 0)];
+        let empty = candidate("3v3", 0, None);
+        let started = candidate("3v3", 2, Some(1.2));
+        let assignments = rules().assign(&tickets, &[empty, started.clone()], 0);
+        assert_eq!(assignments, vec![Assignment { ticket_id: tickets[0].id, session_id: started.id }]);
+    }
+
+    #[test]
+    fn empty_sessions_are_used_when_no_session_is_close_enough() {
+        let tickets = [ticket(1, "3v3", 1.0, 0)];
+        let empty = candidate("3v3", 0, None);
+        let distant = candidate("3v3", 2, Some(4.0));
+        let assignments = rules().assign(&tickets, &[distant, empty.clone()], 0);
+        assert_eq!(assignments, vec![Assignment { ticket_id: tickets[0].id, session_id: empty.id }]);
+    }
+
+    #[test]
+    fn parties_larger_than_the_team_room_are_rejected() {
+        let tickets = [ticket(3, "3v3", 1.0, 0)];
+        let mut session = candidate("3v3", 1, Some(1.0));
+        session.team_room = 2;
+        assert!(rules().assign(&tickets, &[session.clone()], 0).is_empty());
+
+        session.team_room = 3;
+        assert_eq!(rules().assign(&tickets, &[session], 0).len(), 1);
+    }
+
+    #[test]
+    fn distant_regions_are_only_used_after_waiting() {
+        let rules = rules();
+        let mut waiting = ticket(1, "3v3", 1.0, 0);
+        waiting.pings = HashMap::from([("eu".to_string(), 20), ("na".to_string(), 120)]);
+        let mut session = candidate("3v3", 1, Some(1.0));
+        session.region = Some("na".to_string());
+
+        assert!(rules.assign(&[waiting.clone()], &[session.clone()], rules.region_wait - 1).is_empty());
+        assert_eq!(rules.assign(&[waiting], &[session], rules.region_wait).len(), 1);
+    }
+}
@@ -1,6 +1,10 @@
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 
+use crate::schema::account_audit;
 use crate::schema::achievements;
 use crate::schema::ranks;
 use crate::schema::sessions;
@@ -14,11 +18,19 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password: String,
-    pub salt: String,
     pub kda: f32,
+    pub kills: i32,
+    pub deaths: i32,
+    pub assists: i32,
     pub nb_games: i32,
     pub role_id: Uuid, // Assuming you have a separate table for roles
     pub rank_id: Uuid, // Assuming you have a separate table for ranks
+    pub email_verified: bool,
+    pub preferred_region: Option<String>,
+    // Hidden skill rating, see rating.rs
+    pub rating: f32,
+    pub rating_deviation: f32,
+    pub rating_volatility: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize, diesel::Queryable, diesel::Insertable)]
@@ -43,14 +55,20 @@ pub struct Rank {
     pub id: Uuid,
     pub name: String,
     pub image_url: String,
+    pub tier: i32,
+    pub min_rating: f32,
+    pub division: Option<i32>,
+    pub is_default: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, diesel::Queryable, diesel::Insertable,Clone)]
 #[diesel(table_name = sessions)]
 pub struct DBSession {
     pub id: Uuid,
-    pub average_kda: f32,
-    pub average_rank: Uuid,
+    pub game_mode_id: Uuid,
+    pub average_kda: Option<f32>,
+    pub average_rank: Option<Uuid>,
+    pub average_tier: Option<f32>,
     pub is_empty: bool,
 }
 
@@ -59,25 +77,330 @@ pub struct NewUser {
     pub username: String,
     pub email: String,
     pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountTokenRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+// Account created by an admin, which can be given any role
+#[derive(Debug, Deserialize)]
+pub struct NewPrivilegedUser {
+    #[serde(flatten)]
+    pub user: NewUser,
     pub role_name: String,
 }
 
+#[derive(Debug, diesel::Insertable)]
+#[diesel(table_name = account_audit)]
+pub struct AccountAudit {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub role_id: Uuid,
+    pub created_by: Option<Uuid>,
+    pub method: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct LoginUser {
     pub username: String,
     pub password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub all_sessions: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, diesel::Queryable, Clone)]
+pub struct GameMode {
+    pub id: Uuid,
+    pub name: String,
+    pub min_players: i32,
+    pub max_players: i32,
+    pub team_count: i32,
+    pub ranked: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GameModesResponse {
+    pub game_modes: Vec<GameMode>,
+}
+
+// Session declared by a dedicated server
+#[derive(Debug, Deserialize)]
+pub struct NewSession {
+    pub server_address: String,
+    pub game_mode: String,
+    pub port: Option<u16>,
+    pub map: Option<String>,
+    pub region: Option<String>,
+    // Private sessions are left out of matchmaking, players join them by id only
+    #[serde(default)]
+    pub private: bool,
+}
+
+// What a server advertises about its session, the fields left out keep their value
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SessionAdvertisement {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub map: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    // Players connected to the server, as last reported by it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connected_players: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private: Option<bool>,
+}
+
+// Lifecycle of a session, only waiting sessions accept players
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    #[default]
+    Waiting,
+    Starting,
+    InProgress,
+    Finished,
+    Abandoned,
+}
+
+// Session as stored in Redis, `max_players` and the number of teams come from its game mode
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Session {
     pub server_address: String,
-    pub players: Vec<String>,
+    pub game_mode: String,
+    #[serde(default)]
+    pub state: SessionState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub map: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connected_players: Option<usize>,
+    #[serde(default)]
+    pub private: bool,
+    pub max_players: usize,
+    #[serde(deserialize_with = "deserialize_teams")]
+    pub teams: Vec<Vec<String>>,
+    // Total skill of the players of each team
+    pub team_skill: Vec<f32>,
+}
+
+// The Lua scripts updating sessions encode empty teams as `{}` instead of `[]`
+fn deserialize_teams<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<String>>, D::Error> {
+    let teams: Vec<serde_json::Value> = Vec::deserialize(deserializer)?;
+    teams
+        .into_iter()
+        .map(|team| match team {
+            serde_json::Value::Object(map) if map.is_empty() => Ok(Vec::new()),
+            team => serde_json::from_value(team).map_err(serde::de::Error::custom),
+        })
+        .collect()
+}
+
+// Sent by a server to show it is alive, optionally moving its session to another state
+#[derive(Debug, Deserialize)]
+pub struct SessionHeartbeat {
+    pub state: Option<SessionState>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeartbeatResponse {
+    pub session_id: Uuid,
+    pub state: SessionState,
+    // Seconds without heartbeat after which the session is abandoned
+    pub heartbeat_timeout: u64,
 }
 
+#[derive(Debug, Serialize)]
+pub struct TeamPlayer {
+    pub id: Uuid,
+    pub username: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeamResponse {
+    pub team: usize,
+    pub skill: f32,
+    pub players: Vec<TeamPlayer>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionTeamsResponse {
+    pub session_id: Uuid,
+    pub game_mode: String,
+    pub teams: Vec<TeamResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueRequest {
+    pub game_mode: String,
+    // Round trip in milliseconds measured by the client to each region
+    #[serde(default)]
+    pub pings: HashMap<String, u32>,
+    // Region to play in when no ping is sent, defaults to the preferred region of the player
+    pub region: Option<String>,
+    // Usernames of the friends queued along with the player, they must have accepted to join the party of the player.
+    // The whole party is placed on one team
+    #[serde(default)]
+    pub party: Vec<String>,
+}
+
+// Friend invited to the party of the player, or leader of the party whose invite the player accepts
+#[derive(Debug, Deserialize)]
+pub struct PartyInvite {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreferredRegionUpdate {
+    pub region: Option<String>,
+}
+
+// Where to find a session, `team` is the team of the player when the session was found for one
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SessionResponse {
     pub session_id: Uuid,
-    pub server_address: String
+    pub server_address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<usize>,
+}
+
+// Player leaving a session, `username` lets the server of the session kick another player
+#[derive(Debug, Deserialize)]
+pub struct LeaveSession {
+    pub username: Option<String>,
+}
+
+// State of a player in the matchmaking queue
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum QueueStatus {
+    Searching {
+        ticket_id: Uuid,
+        game_mode: String,
+        waiting_for: i64,
+        skill_window: f32,
+    },
+    Matched(SessionResponse),
+    // The matchmaker found a session but could not place the players in it, the error code says why
+    Failed {
+        code: String,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+// Statistics of a player at the end of a match, with the outcome of their team
+#[derive(Debug, Deserialize)]
+pub struct PlayerResult {
+    pub username: String,
+    pub kills: u32,
+    pub deaths: u32,
+    pub assists: u32,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub achievements: Vec<Uuid>,
+}
+
+// Result of a match sent by the server of its session
+#[derive(Debug, Deserialize)]
+pub struct MatchResult {
+    pub duration_seconds: Option<u32>,
+    pub players: Vec<PlayerResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Pagination {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+// A match as seen by one of its players
+#[derive(Debug, Serialize)]
+pub struct PlayerMatch {
+    pub match_id: Uuid,
+    pub game_mode: String,
+    pub map: Option<String>,
+    pub duration_seconds: Option<i32>,
+    pub played_at: NaiveDateTime,
+    pub team: i32,
+    pub outcome: Outcome,
+    pub kills: i32,
+    pub deaths: i32,
+    pub assists: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayerMatchesResponse {
+    pub matches: Vec<PlayerMatch>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchParticipant {
+    pub username: String,
+    pub team: i32,
+    pub outcome: Outcome,
+    pub kills: i32,
+    pub deaths: i32,
+    pub assists: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchResponse {
+    pub match_id: Uuid,
+    pub session_id: Uuid,
+    pub game_mode: String,
+    pub map: Option<String>,
+    pub duration_seconds: Option<i32>,
+    pub played_at: NaiveDateTime,
+    pub participants: Vec<MatchParticipant>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -87,9 +410,12 @@ pub struct AchievementValidation {
 }
 
 #[derive(Debug, Deserialize)]
+// Kills, deaths and assists to add to the counters of a player
 pub struct KdaUpdate {
     pub username: String,
-    pub new_kda: f32,
+    pub kills: u32,
+    pub deaths: u32,
+    pub assists: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -101,7 +427,7 @@ pub struct RankUpdate {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConnectSession {
     pub session_id: Uuid,
-    pub username: String,
+    pub username: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -116,7 +442,7 @@ pub  struct RanksResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FriendRequest {
-    pub username: String,
+    pub username: Option<String>,
     pub friend_username: String,
 }
 
@@ -0,0 +1,149 @@
+// passwords.rs
+use std::io;
+
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version};
+use argon2::password_hash::{PasswordHasher as _, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+
+use crate::config::env_or;
+use crate::errors::ApiError;
+
+// A password hashing scheme, hashes embed their own salt and parameters
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, password: &str) -> Result<String, String>;
+    fn verify(&self, password: &str, hash: &str) -> bool;
+    // Whether the hash was produced by this scheme, whatever its parameters
+    fn recognizes(&self, hash: &str) -> bool;
+    // Whether the hash was produced by this scheme with the configured parameters
+    fn is_current(&self, hash: &str) -> bool;
+}
+
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        // The parameters stored in the hash are used, not the configured ones
+        match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn is_current(&self, hash: &str) -> bool {
+        let hash = match PasswordHash::new(hash) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        match Params::try_from(&hash) {
+            Ok(params) => {
+                hash.algorithm == Algorithm::Argon2id.ident()
+                    && params.m_cost() == self.params.m_cost()
+                    && params.t_cost() == self.params.t_cost()
+                    && params.p_cost() == self.params.p_cost()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<String, String> {
+        bcrypt::hash(password, self.cost).map_err(|e| e.to_string())
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$2")
+    }
+
+    fn is_current(&self, hash: &str) -> bool {
+        // `$2b$12$...`, the cost is the second field
+        hash.split('$').nth(2).and_then(|cost| cost.parse::<u32>().ok()) == Some(self.cost)
+    }
+}
+
+pub struct Verification {
+    pub valid: bool,
+    // The password is right but its hash uses an older scheme or older parameters
+    pub needs_rehash: bool,
+}
+
+// Hashes new passwords with the configured scheme and still verifies the hashes of the other ones
+//
+// Configured through the environment :
+// - PASSWORD_HASHER : `argon2id` or `bcrypt` (default `argon2id`)
+// - ARGON2_MEMORY_KIB / ARGON2_ITERATIONS / ARGON2_PARALLELISM : argon2id cost (default 19456 / 2 / 1)
+// - BCRYPT_COST : bcrypt cost (default 12)
+pub struct Passwords {
+    current: Box<dyn PasswordHasher>,
+    legacy: Vec<Box<dyn PasswordHasher>>,
+}
+
+impl Passwords {
+    pub fn from_env() -> io::Result<Passwords> {
+        let params = Params::new(
+            env_or("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST)?,
+            env_or("ARGON2_ITERATIONS", Params::DEFAULT_T_COST)?,
+            env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST)?,
+            None,
+        )
+        .map_err(|e| io::Error::other(format!("Invalid argon2 parameters : {}", e)))?;
+        let argon2id = Box::new(Argon2idHasher { params });
+        let bcrypt = Box::new(BcryptHasher { cost: env_or("BCRYPT_COST", bcrypt::DEFAULT_COST)? });
+
+        let hasher: String = env_or("PASSWORD_HASHER", "argon2id".to_string())?;
+        match hasher.as_str() {
+            "argon2id" => Ok(Passwords { current: argon2id, legacy: vec![bcrypt] }),
+            "bcrypt" => Ok(Passwords { current: bcrypt, legacy: vec![argon2id] }),
+            other => Err(io::Error::other(format!("Unknown PASSWORD_HASHER : {}", other))),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, ApiError> {
+        self.current
+            .hash(password)
+            .map_err(|e| ApiError::Internal(format!("Failed to hash password : {}", e)))
+    }
+
+    //A malformed or unknown hash is treated as a wrong password
+    pub fn verify(&self, password: &str, hash: &str) -> Verification {
+        if self.current.recognizes(hash) {
+            let valid = self.current.verify(password, hash);
+            return Verification { valid, needs_rehash: valid && !self.current.is_current(hash) };
+        }
+        let valid = self
+            .legacy
+            .iter()
+            .find(|hasher| hasher.recognizes(hash))
+            .map(|hasher| hasher.verify(password, hash))
+            .unwrap_or(false);
+        Verification { valid, needs_rehash: valid }
+    }
+}
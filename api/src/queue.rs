@@ -0,0 +1,350 @@
+// queue.rs
+use std::time::Duration;
+
+use actix_web::web::Data;
+use chrono::Utc;
+use diesel::PgConnection;
+use diesel::r2d2::{self, ConnectionManager};
+use redis::{AsyncCommands, Client};
+use redis::aio::MultiplexedConnection;
+use uuid::Uuid;
+
+use crate::errors::{ApiError, Resource};
+use crate::matchmaking::{Candidate, MatchmakingRules, Ticket};
+use crate::models::{QueueStatus, Session, SessionResponse};
+use crate::session_store;
+
+type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+// Matchmaking queue kept in Redis :
+// - `matchmaking:tickets` : hash of the waiting tickets by id, tickets queued for more than TICKET_LIFETIME are dropped
+//   whether their players still poll or not
+// - `matchmaking:player:{user_id}` : id of the ticket of a queued player
+// - `matchmaking:assignment:{user_id}` : session found for a player, kept until polled or expired
+// - `matchmaking:failure:{user_id}` : why the matchmaker could not place a player, kept until queued again or expired
+// - `matchmaking:invite:{user_id}:{leader_id}` : invite of a player to the party of a leader, kept until accepted or expired
+// - `matchmaking:party:{user_id}` : leader of the party a player accepted to join, only this leader can queue them
+const TICKETS_KEY: &str = "matchmaking:tickets";
+const LOCK_KEY: &str = "matchmaking:lock";
+const TICKET_LIFETIME: u64 = 3600;
+const ASSIGNMENT_LIFETIME: u64 = 300;
+const INVITE_LIFETIME: u64 = 300;
+const PARTY_LIFETIME: u64 = 3600;
+
+// Release the lock KEYS[1] only if it still holds the token ARGV[1], a run slower than the lock lifetime must not release
+// the lock another instance took since
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+fn player_ticket_key(user_id: &Uuid) -> String {
+    format!("matchmaking:player:{}", user_id)
+}
+
+fn assignment_key(user_id: &Uuid) -> String {
+    format!("matchmaking:assignment:{}", user_id)
+}
+
+fn failure_key(user_id: &Uuid) -> String {
+    format!("matchmaking:failure:{}", user_id)
+}
+
+fn invite_key(user_id: &Uuid, leader_id: &Uuid) -> String {
+    format!("matchmaking:invite:{}:{}", user_id, leader_id)
+}
+
+fn party_key(user_id: &Uuid) -> String {
+    format!("matchmaking:party:{}", user_id)
+}
+
+//Invite a player to the party of a leader, the invite replaces a previous one of the same leader
+pub async fn invite(con: &mut MultiplexedConnection, leader_id: &Uuid, user_id: &Uuid) -> Result<(), ApiError> {
+    let _: () = con.set_ex(invite_key(user_id, leader_id), 1, INVITE_LIFETIME).await?;
+    Ok(())
+}
+
+//Join the party of a leader who invited the player, leaving the party they were in
+pub async fn accept_invite(con: &mut MultiplexedConnection, user_id: &Uuid, leader_id: &Uuid) -> Result<(), ApiError> {
+    let removed: u32 = con.del(invite_key(user_id, leader_id)).await?;
+    if removed == 0 {
+        return Err(ApiError::NotFound(Resource::PartyInvite));
+    }
+    let _: () = con.set_ex(party_key(user_id), leader_id.to_string(), PARTY_LIFETIME).await?;
+    Ok(())
+}
+
+pub async fn leave_party(con: &mut MultiplexedConnection, user_id: &Uuid) -> Result<(), ApiError> {
+    let _: () = con.del(party_key(user_id)).await?;
+    Ok(())
+}
+
+//Leader of the party the player accepted to join, if any
+pub async fn party_leader(con: &mut MultiplexedConnection, user_id: &Uuid) -> Result<Option<Uuid>, ApiError> {
+    let leader_id: Option<String> = con.get(party_key(user_id)).await?;
+    Ok(leader_id.and_then(|leader_id| Uuid::parse_str(&leader_id).ok()))
+}
+
+//Queue a ticket, refused when one of its players is already queued
+pub async fn enqueue(con: &mut MultiplexedConnection, ticket: &Ticket) -> Result<(), ApiError> {
+    let mut claimed = Vec::new();
+    for player in ticket.players.iter() {
+        let is_new = redis::cmd("SET")
+            .arg(player_ticket_key(player))
+            .arg(ticket.id.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(TICKET_LIFETIME)
+            .query_async::<_, Option<String>>(con)
+            .await?
+            .is_some();
+        if !is_new {
+            for claimed_player in claimed.iter() {
+                let _: () = con.del(player_ticket_key(claimed_player)).await?;
+            }
+            return Err(ApiError::AlreadyQueued);
+        }
+        claimed.push(*player);
+    }
+
+    for player in ticket.players.iter() {
+        let _: () = con.del(assignment_key(player)).await?;
+        let _: () = con.del(failure_key(player)).await?;
+    }
+    let _: () = con
+        .hset(TICKETS_KEY, ticket.id.to_string(), serde_json::to_string(ticket)?)
+        .await?;
+    Ok(())
+}
+
+async fn load_ticket(con: &mut MultiplexedConnection, ticket_id: &str) -> Result<Option<Ticket>, ApiError> {
+    let ticket: Option<String> = con.hget(TICKETS_KEY, ticket_id).await?;
+    Ok(ticket.and_then(|ticket| serde_json::from_str(&ticket).ok()))
+}
+
+//Remove a ticket from the queue, returns false when the matchmaker already took it
+async fn remove_ticket(con: &mut MultiplexedConnection, ticket: &Ticket) -> Result<bool, ApiError> {
+    let removed: u32 = con.hdel(TICKETS_KEY, ticket.id.to_string()).await?;
+    if removed == 0 {
+        return Ok(false);
+    }
+    for player in ticket.players.iter() {
+        let _: () = con.del(player_ticket_key(player)).await?;
+    }
+    Ok(true)
+}
+
+//Take the ticket of the player out of the queue, along with the players queued with them
+pub async fn leave(con: &mut MultiplexedConnection, user_id: &Uuid) -> Result<(), ApiError> {
+    let ticket_id: Option<String> = con.get(player_ticket_key(user_id)).await?;
+    let ticket = match ticket_id {
+        Some(ticket_id) => load_ticket(con, &ticket_id).await?,
+        None => None,
+    };
+    match ticket {
+        Some(ticket) if remove_ticket(con, &ticket).await? => Ok(()),
+        _ => Err(ApiError::NotQueued),
+    }
+}
+
+//Forget the session found for a player, unless the matchmaker found another one since
+pub async fn clear_assignment(con: &mut MultiplexedConnection, user_id: &Uuid, session_id: &Uuid) -> Result<(), ApiError> {
+    let assignment: Option<String> = con.get(assignment_key(user_id)).await?;
+    let assigned_to = assignment.and_then(|assignment| serde_json::from_str::<SessionResponse>(&assignment).ok());
+    if assigned_to.is_some_and(|assignment| assignment.session_id == *session_id) {
+        let _: () = con.del(assignment_key(user_id)).await?;
+    }
+    Ok(())
+}
+
+pub async fn status(
+    con: &mut MultiplexedConnection,
+    rules: &MatchmakingRules,
+    user_id: &Uuid,
+) -> Result<QueueStatus, ApiError> {
+    let assignment: Option<String> = con.get(assignment_key(user_id)).await?;
+    if let Some(assignment) = assignment {
+        return Ok(QueueStatus::Matched(serde_json::from_str(&assignment)?));
+    }
+
+    let failure: Option<String> = con.get(failure_key(user_id)).await?;
+    if let Some(failure) = failure {
+        return Ok(serde_json::from_str(&failure)?);
+    }
+
+    let ticket_id: Option<String> = con.get(player_ticket_key(user_id)).await?;
+    let ticket = match ticket_id {
+        Some(ticket_id) => load_ticket(con, &ticket_id).await?,
+        None => None,
+    };
+    let ticket = ticket.ok_or(ApiError::NotQueued)?;
+    let waited = Utc::now().timestamp() - ticket.enqueued_at;
+    Ok(QueueStatus::Searching {
+        ticket_id: ticket.id,
+        game_mode: ticket.game_mode,
+        waiting_for: waited,
+        skill_window: rules.window(waited),
+    })
+}
+
+//Run the matchmaker every `interval` in the background
+pub fn spawn_worker(pool: DbPool, redis: Data<Client>, rules: Data<MatchmakingRules>, interval: Duration) {
+    actix_rt::spawn(async move {
+        let mut ticker = actix_rt::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = run_once(&pool, &redis, &rules, interval).await {
+                log::error!("Matchmaking failed : {}", e);
+            }
+        }
+    });
+}
+
+//Place the queued tickets in the open sessions, only one API instance does it at a time
+async fn run_once(pool: &DbPool, redis: &Client, rules: &MatchmakingRules, interval: Duration) -> Result<(), ApiError> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    let token = Uuid::new_v4().to_string();
+    let locked: bool = redis::cmd("SET")
+        .arg(LOCK_KEY)
+        .arg(&token)
+        .arg("NX")
+        .arg("PX")
+        .arg(interval.as_millis() as u64 * 5)
+        .query_async::<_, Option<String>>(&mut con)
+        .await?
+        .is_some();
+    if !locked {
+        return Ok(());
+    }
+
+    let result = match_tickets(pool, &mut con, rules).await;
+    let _: i64 = redis::Script::new(UNLOCK_SCRIPT)
+        .key(LOCK_KEY)
+        .arg(&token)
+        .invoke_async(&mut con)
+        .await?;
+    result
+}
+
+async fn match_tickets(pool: &DbPool, con: &mut MultiplexedConnection, rules: &MatchmakingRules) -> Result<(), ApiError> {
+    let tickets: Vec<String> = con.hvals(TICKETS_KEY).await?;
+    let tickets: Vec<Ticket> = tickets.iter().filter_map(|ticket| serde_json::from_str(ticket).ok()).collect();
+
+    //Players queued for too long are not matched anymore, they most likely left without leaving the queue
+    let now = Utc::now().timestamp();
+    let (expired, tickets): (Vec<Ticket>, Vec<Ticket>) = tickets
+        .into_iter()
+        .partition(|ticket| now - ticket.enqueued_at > TICKET_LIFETIME as i64);
+    for ticket in expired.iter() {
+        remove_ticket(con, ticket).await?;
+    }
+    if tickets.is_empty() {
+        return Ok(());
+    }
+
+    let mut conn = pool.get()?;
+    let open_sessions = session_store::open_sessions(&mut conn, con).await?;
+    let mut candidates = Vec::new();
+    for open_session in open_sessions.iter().filter(|open_session| !open_session.session.private) {
+        let players: Vec<Uuid> = open_session
+            .session
+            .players()
+            .filter_map(|player| Uuid::parse_str(player).ok())
+            .collect();
+        let (skill, tier) = if players.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(session_store::average_skill(&mut conn, &players, rules.skill)?),
+                Some(session_store::average_tier(&mut conn, &players)?),
+            )
+        };
+        candidates.push(Candidate {
+            id: open_session.id,
+            game_mode: open_session.session.game_mode.clone(),
+            capacity: open_session.session.max_players,
+            players: open_session.session.player_count(),
+            team_room: open_session.session.largest_team_room(),
+            skill,
+            tier,
+            region: open_session.session.region.clone(),
+        });
+    }
+
+    for assignment in rules.assign(&tickets, &candidates, now) {
+        let ticket = match tickets.iter().find(|ticket| ticket.id == assignment.ticket_id) {
+            Some(ticket) => ticket,
+            None => continue,
+        };
+        let session = match open_sessions.iter().find(|open_session| open_session.id == assignment.session_id) {
+            Some(open_session) => &open_session.session,
+            None => continue,
+        };
+        //A ticket that can't be placed doesn't stop the others from being placed
+        if let Err(e) = place_ticket(&mut conn, con, rules, ticket, &assignment.session_id, session).await {
+            log::error!("Failed to place ticket {} in session {} : {}", ticket.id, assignment.session_id, e);
+        }
+    }
+    Ok(())
+}
+
+//Add the players of a ticket to the session the matchmaker chose for them
+async fn place_ticket(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    rules: &MatchmakingRules,
+    ticket: &Ticket,
+    session_id: &Uuid,
+    session: &Session,
+) -> Result<(), ApiError> {
+    // The players may have left the queue since the tickets were read
+    if !remove_ticket(con, ticket).await? {
+        return Ok(());
+    }
+
+    //The party joins as a whole so it ends up on one team
+    let team = match session_store::join_session(conn, con, session_id, &ticket.players, rules.skill).await {
+        Ok(joined) => joined.team,
+        Err(e @ ApiError::AlreadyInSession) => {
+            //A player of the ticket is in another session, matching them again would fail the same way
+            log::warn!("Ticket {} not placed in session {} : {}", ticket.id, session_id, e);
+            return report_failure(con, ticket, &e).await;
+        }
+        Err(e) => {
+            //The session filled up through /connect in the meantime, the players keep their place in the queue
+            log::warn!("Failed to add ticket {} to session {} : {}", ticket.id, session_id, e);
+            if let Err(requeue_error) = enqueue(con, ticket).await {
+                log::error!("Failed to queue ticket {} again : {}", ticket.id, requeue_error);
+                return report_failure(con, ticket, &e).await;
+            }
+            return Ok(());
+        }
+    };
+
+    let response = serde_json::to_string(&SessionResponse {
+        session_id: *session_id,
+        server_address: session.server_address.clone(),
+        port: session.port,
+        team: Some(team),
+    })?;
+    for player in ticket.players.iter() {
+        let _: () = con
+            .set_ex(assignment_key(player), &response, ASSIGNMENT_LIFETIME)
+            .await?;
+    }
+    Ok(())
+}
+
+//Tell the players of a ticket taken out of the queue why they were not placed, they see it when polling the queue
+async fn report_failure(con: &mut MultiplexedConnection, ticket: &Ticket, error: &ApiError) -> Result<(), ApiError> {
+    let failure = serde_json::to_string(&QueueStatus::Failed {
+        code: error.code().to_string(),
+        message: error.to_string(),
+    })?;
+    for player in ticket.players.iter() {
+        let _: () = con.set_ex(failure_key(player), &failure, ASSIGNMENT_LIFETIME).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+// ranks.rs
+use diesel::prelude::*;
+use diesel::PgConnection;
+use uuid::Uuid;
+
+use crate::errors::{ApiError, Resource};
+use crate::models::Rank;
+use crate::schema::ranks;
+
+// Ranks are ordered by `tier`, every division of a rank being a tier of its own, e.g. Gold III, Gold II then Gold I
+impl Rank {
+    //Name of the rank with its division, e.g. `Gold II`
+    pub fn full_name(&self) -> String {
+        match self.division {
+            Some(division) => format!("{} {}", self.name, roman(division)),
+            None => self.name.clone(),
+        }
+    }
+}
+
+fn roman(number: i32) -> String {
+    const NUMERALS: [(i32, &str); 13] = [
+        (1000, "M"), (900, "CM"), (500, "D"), (400, "CD"), (100, "C"), (90, "XC"),
+        (50, "L"), (40, "XL"), (10, "X"), (9, "IX"), (5, "V"), (4, "IV"), (1, "I"),
+    ];
+    if !(1..4000).contains(&number) {
+        return number.to_string();
+    }
+    let mut rest = number;
+    let mut roman = String::new();
+    for (value, numeral) in NUMERALS.iter() {
+        while rest >= *value {
+            roman.push_str(numeral);
+            rest -= value;
+        }
+    }
+    roman
+}
+
+//Every rank, from the lowest to the highest
+pub fn ordered(conn: &mut PgConnection) -> Result<Vec<Rank>, ApiError> {
+    Ok(ranks::table.order(ranks::tier).load(conn)?)
+}
+
+pub fn find(conn: &mut PgConnection, rank_id: &Uuid) -> Result<Rank, ApiError> {
+    ranks::table
+        .filter(ranks::id.eq(rank_id))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Rank))
+}
+
+//Rank given to new players
+pub fn default_rank(conn: &mut PgConnection) -> Result<Uuid, ApiError> {
+    ranks::table
+        .select(ranks::id)
+        .filter(ranks::is_default.eq(true))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Rank))
+}
+
+//Rank of a player with a conservative rating, the highest one whose threshold it reaches or the lowest rank below every threshold
+//
+//Players are promoted as soon as they reach a threshold but only demoted once they fall `demotion_margin` below the threshold of
+//their current rank, so a player close to a threshold doesn't change rank after every match
+pub fn rank_for_rating(conn: &mut PgConnection, rating: f64, current: &Uuid, demotion_margin: f64) -> Result<Option<Uuid>, ApiError> {
+    let ranks: Vec<(Uuid, i32, f32)> = ranks::table
+        .select((ranks::id, ranks::tier, ranks::min_rating))
+        .order(ranks::tier)
+        .load(conn)?;
+    let reached = ranks
+        .iter()
+        .rev()
+        .find(|(_, _, min_rating)| *min_rating as f64 <= rating)
+        .or(ranks.first());
+    let current = ranks.iter().find(|(id, _, _)| id == current);
+
+    match (reached, current) {
+        (Some((_, reached_tier, _)), Some((current_id, current_tier, current_min_rating)))
+            if reached_tier < current_tier && rating >= *current_min_rating as f64 - demotion_margin =>
+        {
+            Ok(Some(*current_id))
+        }
+        (reached, _) => Ok(reached.map(|(id, _, _)| *id)),
+    }
+}
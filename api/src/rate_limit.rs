@@ -0,0 +1,168 @@
+// rate_limit.rs
+use std::future::{Future, ready, Ready};
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::{Error, HttpRequest};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use redis::{AsyncCommands, Client, RedisError};
+use redis::aio::MultiplexedConnection;
+
+use crate::config::env_or;
+use crate::errors::ApiError;
+
+// Address of the client, used as the key of the per IP limits
+pub fn client_ip(req: &HttpRequest) -> String {
+    req.connection_info().peer_addr().unwrap_or("unknown").to_string()
+}
+
+//Count a hit in a fixed window, returns the number of hits in the window and the seconds left in it
+async fn hit(con: &mut MultiplexedConnection, key: &str, window: u64) -> Result<(u64, u64), RedisError> {
+    let (count, ttl): (u64, i64) = redis::pipe()
+        .atomic()
+        .cmd("SET").arg(key).arg(0).arg("EX").arg(window).arg("NX").ignore()
+        .incr(key, 1)
+        .ttl(key)
+        .query_async(con)
+        .await?;
+    Ok((count, ttl.max(1) as u64))
+}
+
+// Middleware limiting how many requests an IP can make to a route in a window of time
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    name: &'static str,
+    max_requests: u64,
+    window: u64,
+}
+
+impl RateLimit {
+    pub const fn new(name: &'static str, max_requests: u64, window: u64) -> RateLimit {
+        RateLimit { name, max_requests, window }
+    }
+
+    async fn check(&self, req: &HttpRequest) -> Result<(), ApiError> {
+        let redis = req
+            .app_data::<Data<Client>>()
+            .ok_or(ApiError::Internal("Redis client not registered".to_string()))?;
+        let mut con = redis.get_ref().get_multiplexed_async_connection().await?;
+
+        let key = format!("rate_limit:{}:{}", self.name, client_ip(req));
+        let (count, retry_after) = hit(&mut con, &key, self.window).await?;
+        if count > self.max_requests {
+            return Err(ApiError::TooManyRequests(retry_after));
+        }
+        Ok(())
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limit: *self,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limit: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limit = self.limit;
+
+        Box::pin(async move {
+            limit.check(req.request()).await?;
+            service.call(req).await
+        })
+    }
+}
+
+// Lockout of the login after repeated failures, tracked per username and per IP
+//
+// Configured through the environment :
+// - LOGIN_MAX_FAILURES : failures of a username before it is locked (default 5)
+// - LOGIN_IP_MAX_FAILURES : failures from an IP, on any username, before it is locked (default 20)
+// - LOGIN_FAILURE_WINDOW : seconds after which failures are forgotten (default 900)
+// - LOGIN_LOCKOUT_BASE : seconds of the first lockout, doubled on every failure past the limit (default 30)
+// - LOGIN_LOCKOUT_MAX : longest lockout in seconds (default 3600)
+pub struct LoginThrottle {
+    max_failures: u64,
+    ip_max_failures: u64,
+    failure_window: u64,
+    lockout_base: u64,
+    lockout_max: u64,
+}
+
+impl LoginThrottle {
+    pub fn from_env() -> io::Result<LoginThrottle> {
+        Ok(LoginThrottle {
+            max_failures: env_or("LOGIN_MAX_FAILURES", 5)?,
+            ip_max_failures: env_or("LOGIN_IP_MAX_FAILURES", 20)?,
+            failure_window: env_or("LOGIN_FAILURE_WINDOW", 900)?,
+            lockout_base: env_or("LOGIN_LOCKOUT_BASE", 30)?,
+            lockout_max: env_or("LOGIN_LOCKOUT_MAX", 3600)?,
+        })
+    }
+
+    fn subjects(username: &str, ip: &str) -> [String; 2] {
+        [format!("user:{}", username.to_lowercase()), format!("ip:{}", ip)]
+    }
+
+    //Refuse the attempt while the username or the IP is locked
+    pub async fn check(&self, con: &mut MultiplexedConnection, username: &str, ip: &str) -> Result<(), ApiError> {
+        for subject in Self::subjects(username, ip).iter() {
+            let ttl: i64 = con.ttl(format!("login_lock:{}", subject)).await?;
+            if ttl > 0 {
+                return Err(ApiError::TooManyRequests(ttl as u64));
+            }
+        }
+        Ok(())
+    }
+
+    //Count a failed attempt and lock the username or the IP once they are over their limit
+    pub async fn record_failure(&self, con: &mut MultiplexedConnection, username: &str, ip: &str) -> Result<(), ApiError> {
+        let limits = [self.max_failures, self.ip_max_failures];
+        for (subject, max_failures) in Self::subjects(username, ip).iter().zip(limits) {
+            let (failures, _) = hit(con, &format!("login_failures:{}", subject), self.failure_window).await?;
+            if failures >= max_failures {
+                let exponent = (failures - max_failures).min(32) as u32;
+                let lockout = self.lockout_base.saturating_mul(2u64.saturating_pow(exponent)).min(self.lockout_max);
+                let _: () = con.set_ex(format!("login_lock:{}", subject), 1, lockout).await?;
+            }
+        }
+        Ok(())
+    }
+
+    //A successful login forgets the failures of the username, the IP keeps its count
+    pub async fn record_success(&self, con: &mut MultiplexedConnection, username: &str) -> Result<(), ApiError> {
+        let _: () = con.del(format!("login_failures:user:{}", username.to_lowercase())).await?;
+        Ok(())
+    }
+}
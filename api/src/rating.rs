@@ -0,0 +1,192 @@
+// rating.rs
+use std::f64::consts::PI;
+use std::io;
+
+use crate::config::env_or;
+
+// Rating given to new players, with the deviation of a player nothing is known about yet
+pub const DEFAULT_RATING: f64 = 1500.0;
+pub const DEFAULT_DEVIATION: f64 = 350.0;
+pub const DEFAULT_VOLATILITY: f64 = 0.06;
+
+// Ratings are converted to the Glicko-2 scale with this factor
+const SCALE: f64 = 173.7178;
+const CONVERGENCE: f64 = 0.000001;
+
+// Hidden skill of a player : their rating, how uncertain it is and how erratic their results are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Rating {
+    //Rating the player is almost certainly above, the one ranks are given from so new players start low and climb
+    pub fn conservative(&self) -> f64 {
+        self.rating - 2.0 * self.deviation
+    }
+
+    //A team seen as a single opponent, with the average rating and deviation of its players
+    pub fn team(members: &[Rating]) -> Rating {
+        let size = members.len().max(1) as f64;
+        Rating {
+            rating: members.iter().map(|member| member.rating).sum::<f64>() / size,
+            deviation: (members.iter().map(|member| member.deviation.powi(2)).sum::<f64>() / size).sqrt(),
+            volatility: members.iter().map(|member| member.volatility).sum::<f64>() / size,
+        }
+    }
+}
+
+// Glicko-2 rating system, every match is a rating period of its own, kept free of database access
+//
+// Configured through the environment :
+// - RATING_TAU : how much the volatility of a player can change after a match, between 0.3 and 1.2 (default 0.5)
+// - RANK_DEMOTION_MARGIN : rating points a player can fall below the threshold of their rank before being demoted (default 50)
+#[derive(Debug, Clone)]
+pub struct RatingSystem {
+    tau: f64,
+    pub demotion_margin: f64,
+}
+
+fn g(deviation: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * deviation.powi(2) / PI.powi(2)).sqrt()
+}
+
+fn expected_score(rating: f64, opponent: f64, opponent_deviation: f64) -> f64 {
+    1.0 / (1.0 + (-g(opponent_deviation) * (rating - opponent)).exp())
+}
+
+impl RatingSystem {
+    pub fn from_env() -> io::Result<RatingSystem> {
+        let tau: f64 = env_or("RATING_TAU", 0.5)?;
+        if !(tau > 0.0 && tau.is_finite()) {
+            return Err(io::Error::other(format!("Invalid value for RATING_TAU : {}", tau)));
+        }
+        Ok(RatingSystem {
+            tau,
+            demotion_margin: env_or("RANK_DEMOTION_MARGIN", 50.0)?,
+        })
+    }
+
+    //Rating of a player after the games of a match, each game being an opponent and the score of the player against
+    //them : 1 for a win, 0.5 for a draw and 0 for a loss
+    pub fn update(&self, player: &Rating, games: &[(Rating, f64)]) -> Rating {
+        let mu = (player.rating - DEFAULT_RATING) / SCALE;
+        let phi = player.deviation / SCALE;
+        let sigma = player.volatility;
+        if games.is_empty() {
+            return Rating {
+                deviation: (phi.powi(2) + sigma.powi(2)).sqrt() * SCALE,
+                ..*player
+            };
+        }
+
+        let games: Vec<(f64, f64, f64)> = games
+            .iter()
+            .map(|(opponent, score)| ((opponent.rating - DEFAULT_RATING) / SCALE, opponent.deviation / SCALE, *score))
+            .collect();
+        let variance = 1.0
+            / games
+                .iter()
+                .map(|(opponent_mu, opponent_phi, _)| {
+                    let expected = expected_score(mu, *opponent_mu, *opponent_phi);
+                    g(*opponent_phi).powi(2) * expected * (1.0 - expected)
+                })
+                .sum::<f64>();
+        let improvement: f64 = games
+            .iter()
+            .map(|(opponent_mu, opponent_phi, score)| g(*opponent_phi) * (score - expected_score(mu, *opponent_mu, *opponent_phi)))
+            .sum();
+        let delta = variance * improvement;
+
+        let volatility = self.volatility(phi, sigma, variance, delta);
+        let pre_period_phi = (phi.powi(2) + volatility.powi(2)).sqrt();
+        let new_phi = 1.0 / (1.0 / pre_period_phi.powi(2) + 1.0 / variance).sqrt();
+        let new_mu = mu + new_phi.powi(2) * improvement;
+
+        Rating {
+            rating: new_mu * SCALE + DEFAULT_RATING,
+            deviation: (new_phi * SCALE).min(DEFAULT_DEVIATION),
+            volatility,
+        }
+    }
+
+    //New volatility of a player, found with the Illinois algorithm as in the Glicko-2 paper
+    fn volatility(&self, phi: f64, sigma: f64, variance: f64, delta: f64) -> f64 {
+        let a = sigma.powi(2).ln();
+        let tau = self.tau;
+        let f = |x: f64| {
+            let ex = x.exp();
+            ex * (delta.powi(2) - phi.powi(2) - variance - ex) / (2.0 * (phi.powi(2) + variance + ex).powi(2)) - (x - a) / tau.powi(2)
+        };
+
+        let mut lower = a;
+        let mut upper = if delta.powi(2) > phi.powi(2) + variance {
+            (delta.powi(2) - phi.powi(2) - variance).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+
+        let mut f_lower = f(lower);
+        let mut f_upper = f(upper);
+        while (upper - lower).abs() > CONVERGENCE {
+            let next = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+            let f_next = f(next);
+            if f_next * f_upper <= 0.0 {
+                lower = upper;
+                f_lower = f_upper;
+            } else {
+                f_lower /= 2.0;
+            }
+            upper = next;
+            f_upper = f_next;
+        }
+        (lower / 2.0).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system() -> RatingSystem {
+        RatingSystem {
+            tau: 0.5,
+            demotion_margin: 50.0,
+        }
+    }
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating {
+            rating,
+            deviation,
+            volatility: DEFAULT_VOLATILITY,
+        }
+    }
+
+    #[test]
+    fn glicko2_paper_example() {
+        let player = rating(1500.0, 200.0);
+        let games = [(rating(1400.0, 30.0), 1.0), (rating(1550.0, 100.0), 0.0), (rating(1700.0, 300.0), 0.0)];
+        let updated = system().update(&player, &games);
+
+        assert!((updated.rating - 1464.06).abs() < 0.1, "rating {}", updated.rating);
+        assert!((updated.deviation - 151.52).abs() < 0.1, "deviation {}", updated.deviation);
+        assert!((updated.volatility - 0.05999).abs() < 0.0001, "volatility {}", updated.volatility);
+    }
+
+    #[test]
+    fn no_games_only_grows_the_deviation() {
+        let player = rating(1600.0, 100.0);
+        let updated = system().update(&player, &[]);
+
+        assert_eq!(updated.rating, player.rating);
+        assert_eq!(updated.volatility, player.volatility);
+        assert!(updated.deviation > player.deviation);
+    }
+}
@@ -0,0 +1,494 @@
+// results.rs
+use diesel::prelude::*;
+use diesel::PgConnection;
+use uuid::Uuid;
+
+use std::cmp::Ordering;
+
+use chrono::NaiveDateTime;
+
+use crate::errors::{ApiError, FieldError, Resource};
+use crate::models::{MatchParticipant, MatchResponse, MatchResult, Outcome, PlayerMatch, PlayerMatchesResponse, Session, UserAchievement};
+use crate::ranks;
+use crate::rating::{Rating, RatingSystem};
+use crate::schema::{game_modes, match_participants, matches, user_achievements, users};
+use crate::session_store;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+impl Outcome {
+    //Name of the outcome in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Win => "win",
+            Outcome::Loss => "loss",
+            Outcome::Draw => "draw",
+        }
+    }
+
+    pub fn from_db(outcome: &str) -> Result<Outcome, ApiError> {
+        [Outcome::Win, Outcome::Loss, Outcome::Draw]
+            .into_iter()
+            .find(|known| known.as_str() == outcome)
+            .ok_or(ApiError::Internal(format!("Unknown match outcome : {}", outcome)))
+    }
+
+    fn standing(&self) -> u8 {
+        match self {
+            Outcome::Win => 2,
+            Outcome::Draw => 1,
+            Outcome::Loss => 0,
+        }
+    }
+
+    //Score against an opponent for the rating : 1 when doing better than them, 0.5 when doing as well and 0 when doing worse
+    fn score_against(&self, opponent: Outcome) -> f64 {
+        match self.standing().cmp(&opponent.standing()) {
+            Ordering::Greater => 1.0,
+            Ordering::Equal => 0.5,
+            Ordering::Less => 0.0,
+        }
+    }
+}
+
+//Kills and assists per death
+pub fn kda(kills: i32, deaths: i32, assists: i32) -> f32 {
+    (kills + assists) as f32 / deaths.max(1) as f32
+}
+
+//Add to the kills, deaths and assists of a player and compute their kda again, to be called in a transaction
+pub fn add_kda(conn: &mut PgConnection, user_id: &Uuid, kills: u32, deaths: u32, assists: u32) -> Result<(), ApiError> {
+    let (total_kills, total_deaths, total_assists): (i32, i32, i32) = users::table
+        .select((users::kills, users::deaths, users::assists))
+        .filter(users::id.eq(user_id))
+        .for_update()
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::User))?;
+
+    let total_kills = total_kills.saturating_add_unsigned(kills);
+    let total_deaths = total_deaths.saturating_add_unsigned(deaths);
+    let total_assists = total_assists.saturating_add_unsigned(assists);
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::kills.eq(total_kills),
+            users::deaths.eq(total_deaths),
+            users::assists.eq(total_assists),
+            users::kda.eq(kda(total_kills, total_deaths, total_assists)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn is_recorded(conn: &mut PgConnection, session_id: &Uuid) -> Result<bool, ApiError> {
+    let recorded: i64 = matches::table
+        .filter(matches::session_id.eq(session_id))
+        .count()
+        .get_result(conn)?;
+    Ok(recorded > 0)
+}
+
+//Sides of a match from the team of each player, in game modes with a single team every player is on their own
+fn sides(teams: &[usize], team_count: usize) -> Vec<usize> {
+    if team_count == 1 {
+        (0..teams.len()).collect()
+    } else {
+        teams.to_vec()
+    }
+}
+
+//Indexes of the players whose outcome differs from the one of the first player of their side
+fn inconsistent_outcomes(sides: &[usize], outcomes: &[Outcome]) -> Vec<usize> {
+    (0..sides.len())
+        .filter(|index| {
+            let first = sides.iter().position(|side| *side == sides[*index]).unwrap_or(*index);
+            outcomes[first] != outcomes[*index]
+        })
+        .collect()
+}
+
+//Players of the session the result leaves out
+fn missing_players<'a>(session: &'a Session, player_ids: &[(Uuid, usize)]) -> Vec<&'a String> {
+    session
+        .players()
+        .filter(|player| !player_ids.iter().any(|(player_id, _)| player_id.to_string() == **player))
+        .collect()
+}
+
+//Find the players of a result and their team, every player of the session must be listed once with the same outcome
+//as the rest of their team
+fn resolve_players(conn: &mut PgConnection, session: &Session, result: &MatchResult) -> Result<Vec<(Uuid, usize)>, ApiError> {
+    let mut errors = Vec::new();
+    let mut player_ids = Vec::new();
+    for (index, player) in result.players.iter().enumerate() {
+        let field = format!("players[{}].username", index);
+        let user_id: Option<Uuid> = users::table
+            .select(users::id)
+            .filter(users::username.eq(&player.username))
+            .first(conn)
+            .optional()?;
+        match user_id {
+            None => errors.push(FieldError::new(&field, "not_found", "User not found".to_string())),
+            Some(user_id) if player_ids.iter().any(|(player_id, _)| *player_id == user_id) => {
+                errors.push(FieldError::new(&field, "duplicate", "Player listed more than once".to_string()))
+            }
+            Some(user_id) => {
+                let player_key = user_id.to_string();
+                match session.teams.iter().position(|team| team.contains(&player_key)) {
+                    None => errors.push(FieldError::new(&field, "not_in_session", "Player not in the session".to_string())),
+                    Some(team) => player_ids.push((user_id, team)),
+                }
+            }
+        }
+    }
+    for player in missing_players(session, &player_ids) {
+        errors.push(FieldError::new("players", "missing_player", format!("Player {} of the session has no result", player)));
+    }
+
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    let teams: Vec<usize> = player_ids.iter().map(|(_, team)| *team).collect();
+    let outcomes: Vec<Outcome> = result.players.iter().map(|player| player.outcome).collect();
+    let errors: Vec<FieldError> = inconsistent_outcomes(&sides(&teams, session.teams.len()), &outcomes)
+        .into_iter()
+        .map(|index| {
+            FieldError::new(
+                &format!("players[{}].outcome", index),
+                "inconsistent_outcome",
+                "Players of a team must share the same outcome".to_string(),
+            )
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    Ok(player_ids)
+}
+
+//Record a match and apply its statistics, ratings, ranks and achievements at once, returns false when the result of the session was already applied
+pub fn apply_result(
+    conn: &mut PgConnection,
+    rating_system: &RatingSystem,
+    server_id: &Uuid,
+    session_id: &Uuid,
+    session: &Session,
+    result: &MatchResult,
+) -> Result<bool, ApiError> {
+    let game_mode = session_store::find_game_mode(conn, &session.game_mode)?;
+    let player_ids = resolve_players(conn, session, result)?;
+
+    conn.transaction::<_, ApiError, _>(|conn| {
+        //Claim the session first, a concurrent submission of the same result waits here then applies nothing
+        let match_id: Option<Uuid> = diesel::insert_into(matches::table)
+            .values((
+                matches::session_id.eq(session_id),
+                matches::game_mode_id.eq(game_mode.id),
+                matches::map.eq(&session.map),
+                matches::duration_seconds.eq(result.duration_seconds.map(|duration| duration.min(i32::MAX as u32) as i32)),
+                matches::submitted_by.eq(server_id),
+            ))
+            .on_conflict(matches::session_id)
+            .do_nothing()
+            .returning(matches::id)
+            .get_result(conn)
+            .optional()?;
+        let match_id = match match_id {
+            Some(match_id) => match_id,
+            None => return Ok(false),
+        };
+
+        for ((player_id, team), player) in player_ids.iter().zip(result.players.iter()) {
+            diesel::insert_into(match_participants::table)
+                .values((
+                    match_participants::match_id.eq(match_id),
+                    match_participants::user_id.eq(player_id),
+                    match_participants::team.eq(*team as i32),
+                    match_participants::kills.eq(player.kills.min(i32::MAX as u32) as i32),
+                    match_participants::deaths.eq(player.deaths.min(i32::MAX as u32) as i32),
+                    match_participants::assists.eq(player.assists.min(i32::MAX as u32) as i32),
+                    match_participants::outcome.eq(player.outcome.as_str()),
+                ))
+                .execute(conn)?;
+
+            add_kda(conn, player_id, player.kills, player.deaths, player.assists)?;
+            diesel::update(users::table.filter(users::id.eq(player_id)))
+                .set(users::nb_games.eq(users::nb_games + 1))
+                .execute(conn)?;
+
+            let achievements: Vec<UserAchievement> = player
+                .achievements
+                .iter()
+                .map(|achievement_id| UserAchievement {
+                    user_id: *player_id,
+                    achievement_id: *achievement_id,
+                })
+                .collect();
+            diesel::insert_into(user_achievements::table)
+                .values(&achievements)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+
+        if game_mode.ranked {
+            let teams: Vec<usize> = player_ids.iter().map(|(_, team)| *team).collect();
+            let players: Vec<(Uuid, usize, Outcome)> = player_ids
+                .iter()
+                .zip(sides(&teams, session.teams.len()))
+                .zip(result.players.iter())
+                .map(|(((player_id, _), side), player)| (*player_id, side, player.outcome))
+                .collect();
+            update_ratings(conn, rating_system, &players)?;
+        }
+        Ok(true)
+    })
+}
+
+//Games of each player for the rating, one against every other side seen as a single opponent with the average rating of its players
+fn rating_games(sides: &[usize], outcomes: &[Outcome], ratings: &[Rating]) -> Vec<Vec<(Rating, f64)>> {
+    //Players of a side share the same outcome
+    let mut opponents: Vec<(usize, Rating, Outcome)> = Vec::new();
+    for (side, outcome) in sides.iter().zip(outcomes.iter()) {
+        if opponents.iter().any(|(known, _, _)| known == side) {
+            continue;
+        }
+        let members: Vec<Rating> = sides
+            .iter()
+            .zip(ratings.iter())
+            .filter(|(member_side, _)| *member_side == side)
+            .map(|(_, rating)| *rating)
+            .collect();
+        opponents.push((*side, Rating::team(&members), *outcome));
+    }
+
+    sides
+        .iter()
+        .zip(outcomes.iter())
+        .map(|(side, outcome)| {
+            opponents
+                .iter()
+                .filter(|(opponent_side, _, _)| opponent_side != side)
+                .map(|(_, opponent, opponent_outcome)| (*opponent, outcome.score_against(*opponent_outcome)))
+                .collect()
+        })
+        .collect()
+}
+
+//Update the ratings of the players of a ranked match and move them to the rank of their new rating, to be called in a transaction
+//
+//Players are given with their side, their team or the player alone in game modes with a single team
+fn update_ratings(conn: &mut PgConnection, rating_system: &RatingSystem, players: &[(Uuid, usize, Outcome)]) -> Result<(), ApiError> {
+    let mut ratings = Vec::new();
+    let mut current_ranks = Vec::new();
+    for (player_id, _, _) in players.iter() {
+        let (rating, deviation, volatility, rank_id): (f32, f32, f32, Uuid) = users::table
+            .select((users::rating, users::rating_deviation, users::rating_volatility, users::rank_id))
+            .filter(users::id.eq(player_id))
+            .for_update()
+            .first(conn)
+            .optional()?
+            .ok_or(ApiError::NotFound(Resource::User))?;
+        ratings.push(Rating {
+            rating: rating as f64,
+            deviation: deviation as f64,
+            volatility: volatility as f64,
+        });
+        current_ranks.push(rank_id);
+    }
+
+    let sides: Vec<usize> = players.iter().map(|(_, side, _)| *side).collect();
+    let outcomes: Vec<Outcome> = players.iter().map(|(_, _, outcome)| *outcome).collect();
+    let games = rating_games(&sides, &outcomes, &ratings);
+
+    for (((player_id, _, _), (rating, games)), current_rank) in players.iter().zip(ratings.iter().zip(games.iter())).zip(current_ranks.iter()) {
+        let new_rating = rating_system.update(rating, games);
+
+        diesel::update(users::table.filter(users::id.eq(player_id)))
+            .set((
+                users::rating.eq(new_rating.rating as f32),
+                users::rating_deviation.eq(new_rating.deviation as f32),
+                users::rating_volatility.eq(new_rating.volatility as f32),
+            ))
+            .execute(conn)?;
+        if let Some(rank_id) = ranks::rank_for_rating(conn, new_rating.conservative(), current_rank, rating_system.demotion_margin)? {
+            diesel::update(users::table.filter(users::id.eq(player_id)))
+                .set(users::rank_id.eq(rank_id))
+                .execute(conn)?;
+        }
+    }
+    Ok(())
+}
+
+//Matches of a player, the most recent first
+pub fn player_matches(conn: &mut PgConnection, user_id: &Uuid, page: u32, per_page: u32) -> Result<PlayerMatchesResponse, ApiError> {
+    let total: i64 = match_participants::table
+        .filter(match_participants::user_id.eq(user_id))
+        .count()
+        .get_result(conn)?;
+
+    type Row = (Uuid, String, Option<String>, Option<i32>, NaiveDateTime, i32, String, i32, i32, i32);
+    let rows: Vec<Row> = match_participants::table
+        .inner_join(matches::table.inner_join(game_modes::table))
+        .select((
+            matches::id,
+            game_modes::name,
+            matches::map,
+            matches::duration_seconds,
+            matches::played_at,
+            match_participants::team,
+            match_participants::outcome,
+            match_participants::kills,
+            match_participants::deaths,
+            match_participants::assists,
+        ))
+        .filter(match_participants::user_id.eq(user_id))
+        .order((matches::played_at.desc(), matches::id))
+        .limit(per_page as i64)
+        .offset((page.saturating_sub(1) as i64) * per_page as i64)
+        .load(conn)?;
+
+    let matches = rows
+        .into_iter()
+        .map(|(match_id, game_mode, map, duration_seconds, played_at, team, outcome, kills, deaths, assists)| {
+            Ok(PlayerMatch {
+                match_id,
+                game_mode,
+                map,
+                duration_seconds,
+                played_at,
+                team,
+                outcome: Outcome::from_db(&outcome)?,
+                kills,
+                deaths,
+                assists,
+            })
+        })
+        .collect::<Result<Vec<PlayerMatch>, ApiError>>()?;
+
+    Ok(PlayerMatchesResponse { matches, page, per_page, total })
+}
+
+pub fn match_details(conn: &mut PgConnection, match_id: &Uuid) -> Result<MatchResponse, ApiError> {
+    let (session_id, game_mode, map, duration_seconds, played_at): (Uuid, String, Option<String>, Option<i32>, NaiveDateTime) =
+        matches::table
+            .inner_join(game_modes::table)
+            .select((matches::session_id, game_modes::name, matches::map, matches::duration_seconds, matches::played_at))
+            .filter(matches::id.eq(match_id))
+            .first(conn)
+            .optional()?
+            .ok_or(ApiError::NotFound(Resource::Match))?;
+
+    let rows: Vec<(String, i32, String, i32, i32, i32)> = match_participants::table
+        .inner_join(users::table)
+        .select((
+            users::username,
+            match_participants::team,
+            match_participants::outcome,
+            match_participants::kills,
+            match_participants::deaths,
+            match_participants::assists,
+        ))
+        .filter(match_participants::match_id.eq(match_id))
+        .order((match_participants::team, users::username))
+        .load(conn)?;
+    let participants = rows
+        .into_iter()
+        .map(|(username, team, outcome, kills, deaths, assists)| {
+            Ok(MatchParticipant {
+                username,
+                team,
+                outcome: Outcome::from_db(&outcome)?,
+                kills,
+                deaths,
+                assists,
+            })
+        })
+        .collect::<Result<Vec<MatchParticipant>, ApiError>>()?;
+
+    Ok(MatchResponse {
+        match_id: *match_id,
+        session_id,
+        game_mode,
+        map,
+        duration_seconds,
+        played_at,
+        participants,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64) -> Rating {
+        Rating {
+            rating,
+            deviation: 100.0,
+            volatility: 0.06,
+        }
+    }
+
+    #[test]
+    fn every_player_of_the_session_needs_a_result() {
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let session: Session = serde_json::from_value(serde_json::json!({
+            "server_address": "127.0.0.1",
+            "game_mode": "3v3",
+            "state": "in_progress",
+            "max_players": 6,
+            "teams": [[first.to_string()], [second.to_string(), third.to_string()]],
+            "team_skill": [0.0, 0.0],
+        }))
+        .unwrap();
+
+        assert!(missing_players(&session, &[(first, 0), (second, 1), (third, 1)]).is_empty());
+        assert_eq!(missing_players(&session, &[(first, 0), (second, 1)]), vec![&third.to_string()]);
+    }
+
+    #[test]
+    fn teammates_must_share_their_outcome() {
+        let sides = sides(&[0, 0, 1, 1], 2);
+        let outcomes = [Outcome::Win, Outcome::Loss, Outcome::Loss, Outcome::Loss];
+        assert_eq!(inconsistent_outcomes(&sides, &outcomes), vec![1]);
+    }
+
+    #[test]
+    fn free_for_all_players_are_on_their_own() {
+        //Every player of a single team mode is in the only team
+        let sides = sides(&[0, 0, 0, 0], 1);
+        assert_eq!(sides, vec![0, 1, 2, 3]);
+
+        let outcomes = [Outcome::Loss, Outcome::Win, Outcome::Loss, Outcome::Loss];
+        assert!(inconsistent_outcomes(&sides, &outcomes).is_empty());
+
+        let ratings = [rating(1500.0), rating(1600.0), rating(1400.0), rating(1550.0)];
+        let games = rating_games(&sides, &outcomes, &ratings);
+        assert_eq!(games.len(), 4);
+
+        //The winner beat every other player
+        assert_eq!(games[1].len(), 3);
+        assert!(games[1].iter().all(|(_, score)| *score == 1.0));
+
+        //Each loser lost to the winner and tied with the other losers
+        for (index, player_games) in games.iter().enumerate().filter(|(index, _)| *index != 1) {
+            assert_eq!(player_games.len(), 3);
+            for (opponent, score) in player_games.iter() {
+                let expected = if *opponent == ratings[1] { 0.0 } else { 0.5 };
+                assert_eq!(*score, expected, "player {} against {}", index, opponent.rating);
+            }
+            assert!(!player_games.iter().any(|(opponent, _)| *opponent == ratings[index]));
+        }
+    }
+
+    #[test]
+    fn teams_are_a_single_opponent() {
+        let sides = sides(&[0, 0, 1, 1], 2);
+        let outcomes = [Outcome::Win, Outcome::Win, Outcome::Loss, Outcome::Loss];
+        let ratings = [rating(1500.0), rating(1700.0), rating(1400.0), rating(1600.0)];
+        let games = rating_games(&sides, &outcomes, &ratings);
+
+        assert_eq!(games[0], vec![(Rating::team(&ratings[2..]), 1.0)]);
+        assert_eq!(games[3], vec![(Rating::team(&ratings[..2]), 0.0)]);
+    }
+}
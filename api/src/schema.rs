@@ -6,11 +6,18 @@ table! {
         username -> Varchar,
         email -> Varchar,
         password -> Varchar,
-        salt -> Varchar,
         kda -> Float,
+        kills -> Int4,
+        deaths -> Int4,
+        assists -> Int4,
         nb_games -> Int4,
         role_id -> Uuid,
         rank_id -> Uuid,
+        email_verified -> Bool,
+        preferred_region -> Nullable<Varchar>,
+        rating -> Float,
+        rating_deviation -> Float,
+        rating_volatility -> Float,
     }
 }
 
@@ -42,15 +49,59 @@ table! {
         id -> Uuid,
         name -> Varchar,
         image_url -> VarChar,
+        tier -> Int4,
+        min_rating -> Float,
+        division -> Nullable<Int4>,
+        is_default -> Bool,
     }
 }
 
 table! {
     sessions (id) {
         id -> Uuid,
-        average_kda -> Float,
-        average_rank -> Uuid,
+        game_mode_id -> Uuid,
+        average_kda -> Nullable<Float>,
+        average_rank -> Nullable<Uuid>,
+        average_tier -> Nullable<Float>,
         is_empty -> Bool,
+        server_id -> Nullable<Uuid>,
+        state -> Varchar,
+        last_heartbeat -> Timestamp,
+    }
+}
+
+table! {
+    game_modes (id) {
+        id -> Uuid,
+        name -> Varchar,
+        min_players -> Int4,
+        max_players -> Int4,
+        team_count -> Int4,
+        ranked -> Bool,
+    }
+}
+
+table! {
+    matches (id) {
+        id -> Uuid,
+        session_id -> Uuid,
+        game_mode_id -> Uuid,
+        map -> Nullable<Varchar>,
+        duration_seconds -> Nullable<Int4>,
+        submitted_by -> Nullable<Uuid>,
+        played_at -> Timestamp,
+    }
+}
+
+table! {
+    match_participants (match_id, user_id) {
+        match_id -> Uuid,
+        user_id -> Uuid,
+        team -> Int4,
+        kills -> Int4,
+        deaths -> Int4,
+        assists -> Int4,
+        outcome -> Varchar,
     }
 }
 
@@ -68,12 +119,28 @@ table! {
     }
 }
 
+table! {
+    account_audit (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        role_id -> Uuid,
+        created_by -> Nullable<Uuid>,
+        method -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 joinable!(users -> roles (role_id));
 joinable!(users -> ranks (rank_id));
 joinable!(user_achievements -> users (user_id));
 joinable!(user_achievements -> achievements (achievement_id));
 joinable!(sessions -> ranks (average_rank));
+joinable!(sessions -> game_modes (game_mode_id));
+joinable!(sessions -> users (server_id));
 joinable!(friends -> users (friend_id));
+joinable!(matches -> game_modes (game_mode_id));
+joinable!(match_participants -> matches (match_id));
+joinable!(match_participants -> users (user_id));
 joinable!(friend_requests -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
@@ -86,6 +153,18 @@ allow_tables_to_appear_in_same_query!(
     ranks,
 );
 
+allow_tables_to_appear_in_same_query!(
+    sessions,
+    game_modes,
+);
+
+allow_tables_to_appear_in_same_query!(
+    matches,
+    match_participants,
+    game_modes,
+    users,
+);
+
 allow_tables_to_appear_in_same_query!(
     user_achievements,
     achievements,
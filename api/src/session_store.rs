@@ -0,0 +1,704 @@
+// session_store.rs
+use std::io;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use diesel::dsl::{now, IntervalDsl};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use diesel::r2d2::{self, ConnectionManager};
+use redis::{AsyncCommands, Client};
+use redis::aio::MultiplexedConnection;
+use uuid::Uuid;
+
+use crate::config::env_or;
+use crate::errors::{ApiError, FieldError, Resource};
+use crate::matchmaking::SkillMeasure;
+use crate::models::{GameMode, NewSession, Session, SessionAdvertisement, SessionState};
+use crate::queue;
+use crate::schema::{game_modes, ranks, sessions, users};
+
+type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+// `player_session:{user_id}` : last session joined by a player, checked against the session before use
+const PLAYER_SESSION_LIFETIME: u64 = 86400;
+
+fn player_session_key(user_id: &Uuid) -> String {
+    format!("player_session:{}", user_id)
+}
+
+// Heartbeats expected from the servers hosting sessions
+//
+// Configured through the environment :
+// - SESSION_HEARTBEAT_TIMEOUT : seconds without heartbeat after which a session is abandoned (default 60)
+// - SESSION_REAPER_INTERVAL : seconds between two searches for abandoned sessions (default 15)
+pub struct SessionLifetime {
+    pub heartbeat_timeout: u64,
+    pub reaper_interval: Duration,
+}
+
+impl SessionLifetime {
+    pub fn from_env() -> io::Result<SessionLifetime> {
+        Ok(SessionLifetime {
+            heartbeat_timeout: env_or("SESSION_HEARTBEAT_TIMEOUT", 60)?,
+            reaper_interval: Duration::from_secs(env_or("SESSION_REAPER_INTERVAL", 15)?),
+        })
+    }
+
+    //Seconds a session is kept in Redis without heartbeat, long enough for the reaper to abandon it first
+    pub fn ttl(&self) -> u64 {
+        self.heartbeat_timeout.saturating_mul(2).max(1)
+    }
+}
+
+impl SessionState {
+    const LIVE: [SessionState; 3] = [SessionState::Waiting, SessionState::Starting, SessionState::InProgress];
+
+    //Name of the state in the database and in Redis
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Waiting => "waiting",
+            SessionState::Starting => "starting",
+            SessionState::InProgress => "in_progress",
+            SessionState::Finished => "finished",
+            SessionState::Abandoned => "abandoned",
+        }
+    }
+
+    pub fn from_db(state: &str) -> Result<SessionState, ApiError> {
+        SessionState::LIVE
+            .into_iter()
+            .chain([SessionState::Finished, SessionState::Abandoned])
+            .find(|known| known.as_str() == state)
+            .ok_or(ApiError::Internal(format!("Unknown session state : {}", state)))
+    }
+
+    pub fn is_over(&self) -> bool {
+        matches!(self, SessionState::Finished | SessionState::Abandoned)
+    }
+
+    //A game can go back to waiting while starting, a session that is over never changes again
+    pub fn can_become(&self, next: SessionState) -> bool {
+        use SessionState::*;
+        *self == next
+            || matches!(
+                (self, next),
+                (Waiting, Starting | InProgress | Abandoned)
+                    | (Starting, Waiting | InProgress | Abandoned)
+                    | (InProgress, Finished | Abandoned)
+            )
+    }
+}
+
+impl Session {
+    //Empty session hosting a game mode, with one list of players per team
+    pub fn new(session: NewSession, game_mode: &GameMode) -> Session {
+        let team_count = game_mode.team_count.max(1) as usize;
+        Session {
+            server_address: session.server_address,
+            game_mode: game_mode.name.clone(),
+            state: SessionState::Waiting,
+            port: session.port,
+            map: session.map,
+            region: session.region,
+            connected_players: None,
+            private: session.private,
+            max_players: game_mode.max_players as usize,
+            teams: vec![Vec::new(); team_count],
+            team_skill: vec![0.0; team_count],
+        }
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &String> {
+        self.teams.iter().flatten()
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.teams.iter().map(|team| team.len()).sum()
+    }
+
+    //Most players a party can have to fit in one of the teams
+    pub fn largest_team_room(&self) -> usize {
+        let team_size = self.max_players.div_ceil(self.teams.len().max(1));
+        self.teams.iter().map(|team| team_size.saturating_sub(team.len())).max().unwrap_or(0)
+    }
+}
+
+// A registered session with the players already in it
+pub struct OpenSession {
+    pub id: Uuid,
+    pub session: Session,
+}
+
+pub async fn load_session(con: &mut MultiplexedConnection, session_id: &Uuid) -> Result<Session, ApiError> {
+    let session_data: Option<String> = con.get(session_id.to_string()).await?;
+    Ok(serde_json::from_str(&session_data.ok_or(ApiError::NotFound(Resource::Session))?)?)
+}
+
+//Store a session in Redis, it expires unless its server keeps sending heartbeats
+pub async fn store_session(
+    con: &mut MultiplexedConnection,
+    session_id: &Uuid,
+    session: &Session,
+    ttl: u64,
+) -> Result<(), ApiError> {
+    let _: () = con
+        .set_ex(session_id.to_string(), serde_json::to_string(session)?, ttl)
+        .await?;
+    Ok(())
+}
+
+//Every session waiting for players, the ones missing from Redis are skipped
+pub async fn open_sessions(conn: &mut PgConnection, con: &mut MultiplexedConnection) -> Result<Vec<OpenSession>, ApiError> {
+    let session_ids: Vec<Uuid> = sessions::table
+        .select(sessions::id)
+        .filter(sessions::state.eq(SessionState::Waiting.as_str()))
+        .load(conn)?;
+
+    let mut open_sessions = Vec::new();
+    for id in session_ids {
+        match load_session(con, &id).await {
+            Ok(session) => open_sessions.push(OpenSession { id, session }),
+            Err(ApiError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(open_sessions)
+}
+
+pub fn find_game_mode(conn: &mut PgConnection, name: &str) -> Result<GameMode, ApiError> {
+    game_modes::table
+        .filter(game_modes::name.eq(name))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::GameMode))
+}
+
+//Skill of each of the players, measured with their kda or their rating
+fn skills(conn: &mut PgConnection, players: &[Uuid], measure: SkillMeasure) -> Result<Vec<f32>, ApiError> {
+    let skill = match measure {
+        SkillMeasure::Kda => users::table.select(users::kda).filter(users::id.eq_any(players)).load(conn)?,
+        SkillMeasure::Rating => users::table.select(users::rating).filter(users::id.eq_any(players)).load(conn)?,
+    };
+    Ok(skill)
+}
+
+//Total skill of players, used to balance teams
+pub fn total_skill(conn: &mut PgConnection, players: &[Uuid], measure: SkillMeasure) -> Result<f32, ApiError> {
+    Ok(skills(conn, players, measure)?.iter().sum())
+}
+
+//Average skill of players, 0 for an empty list
+pub fn average_skill(conn: &mut PgConnection, players: &[Uuid], measure: SkillMeasure) -> Result<f32, ApiError> {
+    if players.is_empty() {
+        return Ok(0.0);
+    }
+    let skills = skills(conn, players, measure)?;
+    Ok(skills.iter().sum::<f32>() / skills.len().max(1) as f32)
+}
+
+// Add the party ARGV[4..] to one team of the session stored at KEYS[1], ARGV[1] being the total skill of the party,
+// ARGV[2] the id of the session and KEYS[2..] the last session joined by each player of the party, set for ARGV[3] seconds
+//
+// The party goes to the team with the lowest total skill that has room for all of its players, so it is never split.
+// Runs atomically in Redis, returns the index of the team, -1 if the session doesn't exist,
+// -2 if one of the players is already in it or in another live session, -3 if the session is full,
+// -4 if no team has room for the party and -5 if the session is no longer waiting for players
+const JOIN_SCRIPT: &str = r#"
+local data = redis.call('GET', KEYS[1])
+if not data then
+    return -1
+end
+local session = cjson.decode(data)
+if session.state ~= nil and session.state ~= 'waiting' then
+    return -5
+end
+local function contains(teams, player)
+    for _, team in ipairs(teams) do
+        for _, in_team in ipairs(team) do
+            if in_team == player then
+                return true
+            end
+        end
+    end
+    return false
+end
+local size = #ARGV - 3
+for i = 4, #ARGV do
+    if contains(session.teams, ARGV[i]) then
+        return -2
+    end
+    local other_id = redis.call('GET', KEYS[i - 2])
+    if other_id and other_id ~= ARGV[2] then
+        local other = redis.call('GET', other_id)
+        if other then
+            other = cjson.decode(other)
+            if other.state ~= 'finished' and other.state ~= 'abandoned' and contains(other.teams, ARGV[i]) then
+                return -2
+            end
+        end
+    end
+end
+local total = 0
+for _, team in ipairs(session.teams) do
+    total = total + #team
+end
+if total + size > session.max_players then
+    return -3
+end
+local team_size = math.ceil(session.max_players / #session.teams)
+local chosen = nil
+for index, team in ipairs(session.teams) do
+    if #team + size <= team_size then
+        if chosen == nil
+            or session.team_skill[index] < session.team_skill[chosen]
+            or (session.team_skill[index] == session.team_skill[chosen] and #team < #session.teams[chosen]) then
+            chosen = index
+        end
+    end
+end
+if chosen == nil then
+    return -4
+end
+for i = 4, #ARGV do
+    table.insert(session.teams[chosen], ARGV[i])
+    redis.call('SET', KEYS[i - 2], ARGV[2], 'EX', ARGV[3])
+end
+session.team_skill[chosen] = session.team_skill[chosen] + tonumber(ARGV[1])
+redis.call('SET', KEYS[1], cjson.encode(session), 'KEEPTTL')
+return chosen - 1
+"#;
+
+pub struct JoinResult {
+    pub team: usize,
+    pub session_full: bool,
+}
+
+//Add a party to a team of a session and update the session averages
+pub async fn join_session(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    session_id: &Uuid,
+    party: &[Uuid],
+    measure: SkillMeasure,
+) -> Result<JoinResult, ApiError> {
+    //Add the party to the session in redis, in a single step so concurrent joins can't overwrite each other
+    let script = redis::Script::new(JOIN_SCRIPT);
+    let mut script = script.prepare_invoke();
+    script
+        .key(session_id.to_string())
+        .arg(total_skill(conn, party, measure)?)
+        .arg(session_id.to_string())
+        .arg(PLAYER_SESSION_LIFETIME);
+    for player in party.iter() {
+        script.key(player_session_key(player)).arg(player.to_string());
+    }
+    let team: i64 = script.invoke_async(con).await?;
+    let team = match team {
+        -1 => return Err(ApiError::NotFound(Resource::Session)),
+        -2 => return Err(ApiError::AlreadyInSession),
+        -3 | -4 => return Err(ApiError::SessionFull),
+        -5 => return Err(ApiError::SessionNotJoinable),
+        team => team as usize,
+    };
+
+    let session = load_session(con, session_id).await?;
+    let players = update_averages(conn, session_id, &session)?;
+
+    Ok(JoinResult {
+        team,
+        session_full: players >= session.max_players,
+    })
+}
+
+// Remove the player ARGV[1] from the session stored at KEYS[1], ARGV[2] being the skill of the player
+//
+// Players of a match in progress stay in the session so its result can be recorded for all of them.
+// Runs atomically in Redis, returns the index of the team the player was in, -1 if the session doesn't exist,
+// -2 if the player is not in it and -3 if the match of the session is in progress
+const LEAVE_SCRIPT: &str = r#"
+local data = redis.call('GET', KEYS[1])
+if not data then
+    return -1
+end
+local session = cjson.decode(data)
+if session.state == 'in_progress' then
+    return -3
+end
+for index, team in ipairs(session.teams) do
+    for position, player in ipairs(team) do
+        if player == ARGV[1] then
+            table.remove(team, position)
+            session.team_skill[index] = math.max(session.team_skill[index] - tonumber(ARGV[2]), 0)
+            redis.call('SET', KEYS[1], cjson.encode(session), 'KEEPTTL')
+            return index - 1
+        end
+    end
+end
+return -2
+"#;
+
+//Take a player out of a session and update the session averages, returns the team the player was in
+pub async fn leave_session(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    session_id: &Uuid,
+    player: &Uuid,
+    measure: SkillMeasure,
+) -> Result<usize, ApiError> {
+    let script = redis::Script::new(LEAVE_SCRIPT);
+    let team: i64 = script
+        .key(session_id.to_string())
+        .arg(player.to_string())
+        .arg(total_skill(conn, &[*player], measure)?)
+        .invoke_async(con)
+        .await?;
+    let team = match team {
+        -1 => return Err(ApiError::NotFound(Resource::Session)),
+        -2 => return Err(ApiError::NotInSession),
+        -3 => return Err(ApiError::MatchInProgress),
+        team => team as usize,
+    };
+
+    let session = load_session(con, session_id).await?;
+    update_averages(conn, session_id, &session)?;
+    queue::clear_assignment(con, player, session_id).await?;
+    forget_player_session(con, player, session_id).await?;
+    Ok(team)
+}
+
+async fn forget_player_session(con: &mut MultiplexedConnection, player: &Uuid, session_id: &Uuid) -> Result<(), ApiError> {
+    let current: Option<String> = con.get(player_session_key(player)).await?;
+    if current == Some(session_id.to_string()) {
+        let _: () = con.del(player_session_key(player)).await?;
+    }
+    Ok(())
+}
+
+//Session the player is in, if it is still running
+pub async fn current_session(con: &mut MultiplexedConnection, player: &Uuid) -> Result<Option<(Uuid, Session)>, ApiError> {
+    let session_id: Option<String> = con.get(player_session_key(player)).await?;
+    let session_id = match session_id.and_then(|session_id| Uuid::parse_str(&session_id).ok()) {
+        Some(session_id) => session_id,
+        None => return Ok(None),
+    };
+    let session = match load_session(con, &session_id).await {
+        Ok(session) => session,
+        Err(ApiError::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let player = player.to_string();
+    if session.state.is_over() || !session.players().any(|in_session| *in_session == player) {
+        return Ok(None);
+    }
+    Ok(Some((session_id, session)))
+}
+
+//Average tier of the ranks of players, 0 for an empty list
+pub fn average_tier(conn: &mut PgConnection, players: &[Uuid]) -> Result<f32, ApiError> {
+    if players.is_empty() {
+        return Ok(0.0);
+    }
+    let tiers: Vec<i32> = users::table
+        .inner_join(ranks::table)
+        .select(ranks::tier)
+        .filter(users::id.eq_any(players))
+        .load(conn)?;
+    Ok(tiers.iter().sum::<i32>() as f32 / tiers.len().max(1) as f32)
+}
+
+//Rank whose tier is the closest to an average tier
+fn closest_rank(conn: &mut PgConnection, tier: f32) -> Result<Option<Uuid>, ApiError> {
+    let ranks: Vec<(Uuid, i32)> = ranks::table.select((ranks::id, ranks::tier)).load(conn)?;
+    Ok(ranks
+        .iter()
+        .min_by(|(_, a), (_, b)| (*a as f32 - tier).abs().total_cmp(&(*b as f32 - tier).abs()))
+        .map(|(id, _)| *id))
+}
+
+//Store the averages of the players now in the session, returns how many players it has
+fn update_averages(conn: &mut PgConnection, session_id: &Uuid, session: &Session) -> Result<usize, ApiError> {
+    let players = session
+        .players()
+        .map(|player| Uuid::parse_str(player))
+        .collect::<Result<Vec<Uuid>, _>>()
+        .map_err(|e| ApiError::Internal(format!("Invalid player id in session : {}", e)))?;
+
+    let (session_kda, session_tier, session_rank) = if players.is_empty() {
+        (None, None, None)
+    } else {
+        let tier = average_tier(conn, &players)?;
+        (Some(average_skill(conn, &players, SkillMeasure::Kda)?), Some(tier), closest_rank(conn, tier)?)
+    };
+    diesel::update(sessions::table
+        .filter(sessions::id.eq(session_id)))
+        .set((
+            sessions::is_empty.eq(players.is_empty()),
+            sessions::average_kda.eq(session_kda),
+            sessions::average_tier.eq(session_tier),
+            sessions::average_rank.eq(session_rank),
+        ))
+        .execute(conn)?;
+    Ok(players.len())
+}
+
+// Set the state of the session stored at KEYS[1] to ARGV[1] and keep it ARGV[2] more seconds
+//
+// Runs atomically in Redis so players joining at the same time are not lost, returns 0 if the session doesn't exist
+const STATE_SCRIPT: &str = r#"
+local data = redis.call('GET', KEYS[1])
+if not data then
+    return 0
+end
+local session = cjson.decode(data)
+session.state = ARGV[1]
+redis.call('SET', KEYS[1], cjson.encode(session), 'EX', ARGV[2])
+return 1
+"#;
+
+async fn store_state(
+    con: &mut MultiplexedConnection,
+    session_id: &Uuid,
+    state: SessionState,
+    ttl: u64,
+) -> Result<bool, ApiError> {
+    let script = redis::Script::new(STATE_SCRIPT);
+    let found: i64 = script
+        .key(session_id.to_string())
+        .arg(state.as_str())
+        .arg(ttl)
+        .invoke_async(con)
+        .await?;
+    Ok(found == 1)
+}
+
+//Only the server that registered a session can manage it
+pub fn require_owner(conn: &mut PgConnection, server_id: &Uuid, session_id: &Uuid) -> Result<(), ApiError> {
+    let owner: Option<Uuid> = sessions::table
+        .select(sessions::server_id)
+        .filter(sessions::id.eq(session_id))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Session))?;
+    if owner != Some(*server_id) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(())
+}
+
+pub fn session_state(conn: &mut PgConnection, session_id: &Uuid) -> Result<SessionState, ApiError> {
+    let state: String = sessions::table
+        .select(sessions::state)
+        .filter(sessions::id.eq(session_id))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Session))?;
+    SessionState::from_db(&state)
+}
+
+//State of a session that is not over yet, only the server that registered it can change it
+fn live_session_state(conn: &mut PgConnection, server_id: &Uuid, session_id: &Uuid) -> Result<SessionState, ApiError> {
+    let (owner, state): (Option<Uuid>, String) = sessions::table
+        .select((sessions::server_id, sessions::state))
+        .filter(sessions::id.eq(session_id))
+        .first(conn)
+        .optional()?
+        .ok_or(ApiError::NotFound(Resource::Session))?;
+    if owner != Some(*server_id) {
+        return Err(ApiError::Forbidden);
+    }
+
+    let state = SessionState::from_db(&state)?;
+    if state.is_over() {
+        return Err(ApiError::SessionClosed);
+    }
+    Ok(state)
+}
+
+//Keep a session alive and move it to the requested state
+pub async fn heartbeat(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    lifetime: &SessionLifetime,
+    server_id: &Uuid,
+    session_id: &Uuid,
+    requested: Option<SessionState>,
+) -> Result<SessionState, ApiError> {
+    let current = live_session_state(conn, server_id, session_id)?;
+    let next = requested.unwrap_or(current);
+    if !current.can_become(next) {
+        return Err(ApiError::InvalidStateTransition(current.as_str(), next.as_str()));
+    }
+    //A match only starts with the players its game mode needs
+    if next != current && matches!(next, SessionState::Starting | SessionState::InProgress) {
+        let session = load_session(con, session_id).await?;
+        let min_players = find_game_mode(conn, &session.game_mode)?.min_players;
+        if (session.player_count() as i32) < min_players {
+            return Err(ApiError::NotEnoughPlayers(min_players));
+        }
+    }
+
+    //The reaper may have abandoned the session since it was read
+    let updated = diesel::update(sessions::table
+        .filter(sessions::id.eq(session_id))
+        .filter(sessions::state.eq(current.as_str())))
+        .set((sessions::state.eq(next.as_str()), sessions::last_heartbeat.eq(now)))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(ApiError::SessionClosed);
+    }
+
+    if !store_state(con, session_id, next, lifetime.ttl()).await? {
+        return Err(ApiError::NotFound(Resource::Session));
+    }
+    if next.is_over() {
+        free_players(con, session_id).await?;
+    }
+    Ok(next)
+}
+
+// Copy the fields of the JSON object ARGV[1] into the session stored at KEYS[1]
+//
+// Runs atomically in Redis so players joining at the same time are not lost, returns 0 if the session doesn't exist
+const ADVERTISE_SCRIPT: &str = r#"
+local data = redis.call('GET', KEYS[1])
+if not data then
+    return 0
+end
+local session = cjson.decode(data)
+for field, value in pairs(cjson.decode(ARGV[1])) do
+    session[field] = value
+end
+redis.call('SET', KEYS[1], cjson.encode(session), 'KEEPTTL')
+return 1
+"#;
+
+//Update what the server advertises about its session
+pub async fn advertise(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    server_id: &Uuid,
+    session_id: &Uuid,
+    advertisement: &SessionAdvertisement,
+) -> Result<(), ApiError> {
+    live_session_state(conn, server_id, session_id)?;
+    let session = load_session(con, session_id).await?;
+
+    let mut errors = Vec::new();
+    if advertisement.server_address.as_ref().is_some_and(|address| address.trim().is_empty()) {
+        errors.push(FieldError::new("server_address", "required", "Server address can't be empty".to_string()));
+    }
+    if advertisement.port == Some(0) {
+        errors.push(FieldError::new("port", "invalid_port", "Port must be between 1 and 65535".to_string()));
+    }
+    if advertisement.connected_players.is_some_and(|players| players > session.max_players) {
+        errors.push(FieldError::new(
+            "connected_players",
+            "too_many_players",
+            format!("The game mode allows at most {} players", session.max_players),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    let script = redis::Script::new(ADVERTISE_SCRIPT);
+    let found: i64 = script
+        .key(session_id.to_string())
+        .arg(serde_json::to_string(advertisement)?)
+        .invoke_async(con)
+        .await?;
+    if found == 0 {
+        return Err(ApiError::NotFound(Resource::Session));
+    }
+    Ok(())
+}
+
+//Mark a session as finished once its result is in and free its players
+pub async fn finish_session(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    lifetime: &SessionLifetime,
+    session_id: &Uuid,
+) -> Result<(), ApiError> {
+    close_session(conn, con, lifetime, session_id, SessionState::Finished).await
+}
+
+//Mark a session as abandoned when its server gives it up and free its players
+pub async fn abandon_session(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    lifetime: &SessionLifetime,
+    session_id: &Uuid,
+) -> Result<(), ApiError> {
+    close_session(conn, con, lifetime, session_id, SessionState::Abandoned).await
+}
+
+//Move a session that is still live to a final state, a session already over keeps its state
+async fn close_session(
+    conn: &mut PgConnection,
+    con: &mut MultiplexedConnection,
+    lifetime: &SessionLifetime,
+    session_id: &Uuid,
+    state: SessionState,
+) -> Result<(), ApiError> {
+    let updated = diesel::update(sessions::table
+        .filter(sessions::id.eq(session_id))
+        .filter(sessions::state.eq_any(SessionState::LIVE.map(|state| state.as_str()))))
+        .set(sessions::state.eq(state.as_str()))
+        .execute(conn)?;
+    if updated > 0 {
+        store_state(con, session_id, state, lifetime.ttl()).await?;
+    }
+    free_players(con, session_id).await
+}
+
+//Forget the session found by the matchmaker for the players of a session that is over
+async fn free_players(con: &mut MultiplexedConnection, session_id: &Uuid) -> Result<(), ApiError> {
+    let session = match load_session(con, session_id).await {
+        Ok(session) => session,
+        Err(ApiError::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for player in session.players().filter_map(|player| Uuid::parse_str(player).ok()) {
+        queue::clear_assignment(con, &player, session_id).await?;
+        forget_player_session(con, &player, session_id).await?;
+    }
+    Ok(())
+}
+
+//Abandon the sessions of silent servers every `reaper_interval` in the background
+pub fn spawn_reaper(pool: DbPool, redis: Data<Client>, lifetime: Data<SessionLifetime>) {
+    actix_rt::spawn(async move {
+        let mut ticker = actix_rt::time::interval(lifetime.reaper_interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = reap(&pool, &redis, &lifetime).await {
+                log::error!("Session reaping failed : {}", e);
+            }
+        }
+    });
+}
+
+async fn reap(pool: &DbPool, redis: &Client, lifetime: &SessionLifetime) -> Result<(), ApiError> {
+    let mut conn = pool.get()?;
+    //Sessions are abandoned in a single update so concurrent API instances never reap the same one twice
+    let abandoned: Vec<Uuid> = diesel::update(sessions::table
+        .filter(sessions::state.eq_any(SessionState::LIVE.map(|state| state.as_str())))
+        .filter(sessions::last_heartbeat.lt(now - (lifetime.heartbeat_timeout as i64).seconds())))
+        .set(sessions::state.eq(SessionState::Abandoned.as_str()))
+        .returning(sessions::id)
+        .get_results(&mut conn)?;
+    if abandoned.is_empty() {
+        return Ok(());
+    }
+
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    for session_id in abandoned.iter() {
+        log::warn!("Session {} abandoned, no heartbeat for {} seconds", session_id, lifetime.heartbeat_timeout);
+        store_state(&mut con, session_id, SessionState::Abandoned, lifetime.ttl()).await?;
+        free_players(&mut con, session_id).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+// tokens.rs
+use chrono::{Duration, Utc};
+use rand::random;
+use redis::{AsyncCommands, RedisError};
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::errors::ApiError;
+use crate::jwt::{Claims, JwtKeys};
+use crate::models::TokenResponse;
+
+pub const ACCESS_TOKEN_LIFETIME: i64 = 3600;
+pub const REFRESH_TOKEN_LIFETIME: i64 = 30 * 24 * 3600;
+
+// Single-use tokens sent by mail, only the last one issued to a user for a purpose is valid
+#[derive(Debug, Clone, Copy)]
+pub enum AccountToken {
+    EmailVerification,
+    PasswordReset,
+}
+
+impl AccountToken {
+    fn name(&self) -> &'static str {
+        match self {
+            AccountToken::EmailVerification => "email_verification",
+            AccountToken::PasswordReset => "password_reset",
+        }
+    }
+
+    fn lifetime(&self) -> u64 {
+        match self {
+            AccountToken::EmailVerification => 24 * 3600,
+            AccountToken::PasswordReset => 3600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenData {
+    pub user_id: Uuid,
+}
+
+fn refresh_token_key(token: &str) -> String {
+    format!("refresh_token:{}", token)
+}
+
+fn user_refresh_tokens_key(user_id: &Uuid) -> String {
+    format!("user_refresh_tokens:{}", user_id)
+}
+
+fn account_token_key(kind: AccountToken, token: &str) -> String {
+    format!("{}_token:{}", kind.name(), token)
+}
+
+fn user_account_token_key(kind: AccountToken, user_id: &Uuid) -> String {
+    format!("user_{}_token:{}", kind.name(), user_id)
+}
+
+fn revoked_token_key(jti: &str) -> String {
+    format!("revoked_token:{}", jti)
+}
+
+fn revoked_user_key(user_id: &str) -> String {
+    format!("revoked_user:{}", user_id)
+}
+
+// Ids of the access tokens issued in the same second as the last revocation of every token of the user, after it
+fn reissued_tokens_key(user_id: &str) -> String {
+    format!("reissued_tokens:{}", user_id)
+}
+
+fn random_token() -> String {
+    let bytes: [u8; 32] = random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+}
+
+//Issue a new access token and a single-use refresh token for a user
+pub async fn issue_tokens(
+    con: &mut MultiplexedConnection,
+    keys: &JwtKeys,
+    user_id: Uuid,
+    role: String,
+) -> Result<TokenResponse, ApiError> {
+    let now = Utc::now();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        role,
+        exp: (now + Duration::seconds(ACCESS_TOKEN_LIFETIME)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+    };
+    //Tokens only carry their second of issue, one issued in the second of a revocation is told apart by its id
+    let revoked_at: Option<i64> = con.get(revoked_user_key(&claims.sub)).await?;
+    if revoked_at == Some(claims.iat as i64) {
+        let _: () = con.sadd(reissued_tokens_key(&claims.sub), &claims.jti).await?;
+        let _: () = con.expire(reissued_tokens_key(&claims.sub), ACCESS_TOKEN_LIFETIME).await?;
+    }
+
+    let access_token = keys
+        .sign(&claims)
+        .map_err(|e| ApiError::Internal(format!("Failed to sign token : {}", e)))?;
+
+    let refresh_token = random_token();
+    let data = serde_json::to_string(&RefreshTokenData { user_id })?;
+
+    let _: () = con
+        .set_ex(refresh_token_key(&refresh_token), data, REFRESH_TOKEN_LIFETIME as u64)
+        .await?;
+    // Keep track of the refresh tokens of the user so they can all be revoked at once
+    let _: () = con
+        .sadd(user_refresh_tokens_key(&user_id), &refresh_token)
+        .await?;
+    let _: () = con
+        .expire(user_refresh_tokens_key(&user_id), REFRESH_TOKEN_LIFETIME)
+        .await?;
+
+    Ok(TokenResponse {
+        access_token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_LIFETIME,
+    })
+}
+
+//Consume a refresh token, it can't be used again afterwards
+pub async fn take_refresh_token(
+    con: &mut MultiplexedConnection,
+    refresh_token: &str,
+) -> Result<Option<RefreshTokenData>, RedisError> {
+    let data: Option<String> = con.get_del(refresh_token_key(refresh_token)).await?;
+    match data.and_then(|data| serde_json::from_str::<RefreshTokenData>(&data).ok()) {
+        Some(data) => {
+            let _: () = con.srem(user_refresh_tokens_key(&data.user_id), refresh_token).await?;
+            Ok(Some(data))
+        }
+        None => Ok(None),
+    }
+}
+
+// Consume the refresh token KEYS[1] only if it was issued to the user ARGV[1], KEYS[2] being the refresh tokens of the user
+// and ARGV[2] the token itself
+//
+// Runs atomically in Redis, returns 1 when the token is consumed, 0 if it doesn't exist and -1 if it was issued to another user
+const TAKE_OWN_REFRESH_TOKEN_SCRIPT: &str = r#"
+local data = redis.call('GET', KEYS[1])
+if not data then
+    return 0
+end
+if cjson.decode(data).user_id ~= ARGV[1] then
+    return -1
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', KEYS[2], ARGV[2])
+return 1
+"#;
+
+//Consume a refresh token of the user, returns false when it was issued to someone else and is left untouched
+pub async fn take_own_refresh_token(
+    con: &mut MultiplexedConnection,
+    refresh_token: &str,
+    user_id: &Uuid,
+) -> Result<bool, RedisError> {
+    let taken: i64 = redis::Script::new(TAKE_OWN_REFRESH_TOKEN_SCRIPT)
+        .key(refresh_token_key(refresh_token))
+        .key(user_refresh_tokens_key(user_id))
+        .arg(user_id.to_string())
+        .arg(refresh_token)
+        .invoke_async(con)
+        .await?;
+    Ok(taken >= 0)
+}
+
+//Revoke a single access token until it expires
+pub async fn revoke_access_token(con: &mut MultiplexedConnection, claims: &Claims) -> Result<(), RedisError> {
+    let remaining = (claims.exp as i64 - Utc::now().timestamp()).max(1);
+    con.set_ex(revoked_token_key(&claims.jti), 1, remaining as u64).await
+}
+
+//Revoke every access and refresh token issued to a user until now
+pub async fn revoke_user(con: &mut MultiplexedConnection, user_id: &Uuid) -> Result<(), RedisError> {
+    // Access tokens issued before this point are rejected until the last of them expires
+    let _: () = con
+        .set_ex(revoked_user_key(&user_id.to_string()), Utc::now().timestamp(), ACCESS_TOKEN_LIFETIME as u64)
+        .await?;
+    let _: () = con.del(reissued_tokens_key(&user_id.to_string())).await?;
+
+    let refresh_tokens: Vec<String> = con.smembers(user_refresh_tokens_key(user_id)).await?;
+    for refresh_token in refresh_tokens.iter() {
+        let _: () = con.del(refresh_token_key(refresh_token)).await?;
+    }
+    con.del(user_refresh_tokens_key(user_id)).await
+}
+
+pub async fn is_revoked(con: &mut MultiplexedConnection, claims: &Claims) -> Result<bool, RedisError> {
+    let token_revoked: bool = con.exists(revoked_token_key(&claims.jti)).await?;
+    if token_revoked {
+        return Ok(true);
+    }
+    let revoked_at: Option<i64> = con.get(revoked_user_key(&claims.sub)).await?;
+    match revoked_at {
+        Some(revoked_at) if (claims.iat as i64) < revoked_at => Ok(true),
+        Some(revoked_at) if claims.iat as i64 == revoked_at => {
+            let reissued: bool = con.sismember(reissued_tokens_key(&claims.sub), &claims.jti).await?;
+            Ok(!reissued)
+        }
+        _ => Ok(false),
+    }
+}
+
+//Issue a single-use token for the user, replacing the previous one of the same kind
+pub async fn issue_account_token(
+    con: &mut MultiplexedConnection,
+    kind: AccountToken,
+    user_id: &Uuid,
+) -> Result<String, RedisError> {
+    let previous: Option<String> = con.get(user_account_token_key(kind, user_id)).await?;
+    if let Some(previous) = previous {
+        let _: () = con.del(account_token_key(kind, &previous)).await?;
+    }
+
+    let token = random_token();
+    let _: () = con
+        .set_ex(account_token_key(kind, &token), user_id.to_string(), kind.lifetime())
+        .await?;
+    let _: () = con
+        .set_ex(user_account_token_key(kind, user_id), &token, kind.lifetime())
+        .await?;
+    Ok(token)
+}
+
+//Get the user a token was issued to without consuming it
+pub async fn peek_account_token(
+    con: &mut MultiplexedConnection,
+    kind: AccountToken,
+    token: &str,
+) -> Result<Option<Uuid>, RedisError> {
+    let user_id: Option<String> = con.get(account_token_key(kind, token)).await?;
+    Ok(user_id.and_then(|user_id| Uuid::parse_str(&user_id).ok()))
+}
+
+//Consume a token, it can't be used again afterwards
+pub async fn take_account_token(
+    con: &mut MultiplexedConnection,
+    kind: AccountToken,
+    token: &str,
+) -> Result<Option<Uuid>, RedisError> {
+    let user_id: Option<String> = con.get_del(account_token_key(kind, token)).await?;
+    match user_id.and_then(|user_id| Uuid::parse_str(&user_id).ok()) {
+        Some(user_id) => {
+            let _: () = con.del(user_account_token_key(kind, &user_id)).await?;
+            Ok(Some(user_id))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,120 @@
+// validation.rs
+use std::collections::HashSet;
+use std::io;
+
+use email_address::EmailAddress;
+
+use crate::config::{env_list_or, env_or};
+use crate::errors::{ApiError, FieldError};
+use crate::models::NewUser;
+
+// Rules applied to the accounts created through /register
+//
+// Configured through the environment :
+// - USERNAME_MIN_LENGTH / USERNAME_MAX_LENGTH : bounds of the username length (default 3 / 20)
+// - USERNAME_EXTRA_CHARS : characters allowed in usernames besides ASCII letters and digits (default `_-.`)
+// - PASSWORD_MIN_LENGTH : minimum password length (default 8)
+// - PASSWORD_MIN_CLASSES : how many of lowercase, uppercase, digit and symbol a password must mix (default 3)
+// - RESERVED_USERNAMES : `,` separated list of usernames that can't be registered, compared case-insensitively
+pub struct RegistrationPolicy {
+    username_min_length: usize,
+    username_max_length: usize,
+    username_extra_chars: String,
+    password_min_length: usize,
+    password_min_classes: usize,
+    reserved_usernames: HashSet<String>,
+}
+
+impl RegistrationPolicy {
+    pub fn from_env() -> io::Result<RegistrationPolicy> {
+        Ok(RegistrationPolicy {
+            username_min_length: env_or("USERNAME_MIN_LENGTH", 3)?,
+            username_max_length: env_or("USERNAME_MAX_LENGTH", 20)?,
+            username_extra_chars: env_or("USERNAME_EXTRA_CHARS", "_-.".to_string())?,
+            password_min_length: env_or("PASSWORD_MIN_LENGTH", 8)?,
+            password_min_classes: env_or("PASSWORD_MIN_CLASSES", 3)?,
+            reserved_usernames: env_list_or("RESERVED_USERNAMES", &["admin", "administrator", "server", "root", "system", "moderator"])
+                .into_iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+        })
+    }
+
+    //Check every field of a registration and report all the problems at once
+    pub fn validate(&self, user: &NewUser) -> Result<(), ApiError> {
+        let mut errors = Vec::new();
+        self.validate_username(&user.username, &mut errors);
+        if !EmailAddress::is_valid(&user.email) {
+            errors.push(FieldError::new("email", "invalid_email", "Email address is not valid".to_string()));
+        }
+        self.check_password(&user.password, &user.username, &mut errors);
+        Self::result(errors)
+    }
+
+    //Check a new password of an existing account
+    pub fn validate_password(&self, password: &str, username: &str) -> Result<(), ApiError> {
+        let mut errors = Vec::new();
+        self.check_password(password, username, &mut errors);
+        Self::result(errors)
+    }
+
+    fn result(errors: Vec<FieldError>) -> Result<(), ApiError> {
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Validation(errors))
+        }
+    }
+
+    fn validate_username(&self, username: &str, errors: &mut Vec<FieldError>) {
+        let length = username.chars().count();
+        if length < self.username_min_length || length > self.username_max_length {
+            errors.push(FieldError::new(
+                "username",
+                "invalid_length",
+                format!("Username must be between {} and {} characters long", self.username_min_length, self.username_max_length),
+            ));
+        }
+        if !username.chars().all(|c| c.is_ascii_alphanumeric() || self.username_extra_chars.contains(c)) {
+            errors.push(FieldError::new(
+                "username",
+                "invalid_characters",
+                format!("Username can only contain letters, digits and {}", self.username_extra_chars),
+            ));
+        }
+        if self.reserved_usernames.contains(&username.to_lowercase()) {
+            errors.push(FieldError::new("username", "reserved", "Username is reserved".to_string()));
+        }
+    }
+
+    fn check_password(&self, password: &str, username: &str, errors: &mut Vec<FieldError>) {
+        if password.chars().count() < self.password_min_length {
+            errors.push(FieldError::new(
+                "password",
+                "too_short",
+                format!("Password must be at least {} characters long", self.password_min_length),
+            ));
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|present| **present).count() < self.password_min_classes {
+            errors.push(FieldError::new(
+                "password",
+                "too_weak",
+                format!(
+                    "Password must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                    self.password_min_classes
+                ),
+            ));
+        }
+
+        if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+            errors.push(FieldError::new("password", "contains_username", "Password can't contain the username".to_string()));
+        }
+    }
+}
@@ -8,19 +8,40 @@ CREATE TABLE roles (
 CREATE TABLE ranks (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
     name VARCHAR(255) NOT NULL,
-    image_url VARCHAR(255)
+    image_url VARCHAR(255),
+    -- Position of the rank from the lowest one, every division being a tier of its own, used to order and average the ranks
+    tier INT UNIQUE NOT NULL,
+    -- Lowest conservative skill rating of the players of the rank, players move to the rank their rating reaches
+    min_rating REAL UNIQUE NOT NULL,
+    -- Division within the rank, e.g. 3 for Gold III, NULL for a rank without divisions
+    division INT CHECK (division > 0),
+    -- Rank given to new players
+    is_default BOOLEAN NOT NULL DEFAULT FALSE,
+    UNIQUE (name, division)
 );
 
+CREATE UNIQUE INDEX ranks_single_default ON ranks (is_default) WHERE is_default;
+
 CREATE TABLE users (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
     username VARCHAR(100) UNIQUE NOT NULL,
     email VARCHAR(255) UNIQUE NOT NULL,
     password VARCHAR(255) NOT NULL,
-    salt VARCHAR(255) NOT NULL,
+    -- Derived from the kills, deaths and assists counters, updated along with them
     kda FLOAT DEFAULT 0,
+    kills INT NOT NULL DEFAULT 0,
+    deaths INT NOT NULL DEFAULT 0,
+    assists INT NOT NULL DEFAULT 0,
     nb_games INT DEFAULT 0,
     role_id UUID REFERENCES roles(id),
-    rank_id UUID REFERENCES ranks(id)
+    rank_id UUID REFERENCES ranks(id),
+    email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Region the matchmaker looks for when the client sends no ping
+    preferred_region VARCHAR(50),
+    -- Hidden Glicko-2 skill rating, updated by the results of ranked matches
+    rating REAL NOT NULL DEFAULT 1500,
+    rating_deviation REAL NOT NULL DEFAULT 350,
+    rating_volatility REAL NOT NULL DEFAULT 0.06
 );
 
 CREATE TABLE achievements (
@@ -36,13 +57,56 @@ CREATE TABLE user_achievements (
     PRIMARY KEY (user_id, achievement_id)
 );
 
+-- Kinds of sessions a dedicated server can host
+CREATE TABLE game_modes (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name VARCHAR(100) UNIQUE NOT NULL,
+    min_players INT NOT NULL,
+    max_players INT NOT NULL,
+    team_count INT NOT NULL,
+    ranked BOOLEAN NOT NULL DEFAULT TRUE,
+    CHECK (min_players > 0 AND min_players <= max_players AND team_count > 0)
+);
+
 CREATE TABLE sessions (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    game_mode_id UUID REFERENCES game_modes(id) NOT NULL,
     average_kda FLOAT,
     average_rank UUID REFERENCES ranks(id),
-    is_empty BOOLEAN DEFAULT TRUE
+    average_tier REAL,
+    is_empty BOOLEAN DEFAULT TRUE,
+    -- Server account that registered the session, the only one allowed to send its heartbeats
+    server_id UUID REFERENCES users(id),
+    state VARCHAR(20) NOT NULL DEFAULT 'waiting',
+    last_heartbeat TIMESTAMP NOT NULL DEFAULT NOW(),
+    CHECK (state IN ('waiting', 'starting', 'in_progress', 'finished', 'abandoned'))
+);
+
+-- Matches played, one per session so a result submitted twice is applied once
+CREATE TABLE matches (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    session_id UUID UNIQUE NOT NULL,
+    game_mode_id UUID REFERENCES game_modes(id) NOT NULL,
+    map VARCHAR(100),
+    duration_seconds INT,
+    submitted_by UUID REFERENCES users(id),
+    played_at TIMESTAMP NOT NULL DEFAULT NOW()
 );
 
+CREATE TABLE match_participants (
+    match_id UUID REFERENCES matches(id) ON DELETE CASCADE,
+    user_id UUID REFERENCES users(id),
+    team INT NOT NULL,
+    kills INT NOT NULL,
+    deaths INT NOT NULL,
+    assists INT NOT NULL,
+    outcome VARCHAR(10) NOT NULL,
+    PRIMARY KEY (match_id, user_id),
+    CHECK (outcome IN ('win', 'loss', 'draw'))
+);
+
+CREATE INDEX match_participants_user_id_idx ON match_participants (user_id);
+
 CREATE TABLE friends (
     user_id UUID REFERENCES users(id),
     friend_id UUID REFERENCES users(id),
@@ -55,17 +119,40 @@ CREATE Table friend_requests (
     PRIMARY KEY (user_id, friend_id)
 );
 
+-- Accounts created with a role other than the public registration one
+CREATE TABLE account_audit (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID REFERENCES users(id) NOT NULL,
+    role_id UUID REFERENCES roles(id) NOT NULL,
+    created_by UUID REFERENCES users(id),
+    method VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
 INSERT INTO roles (name) VALUES ('server');
 INSERT INTO roles (name) VALUES ('client');
+INSERT INTO roles (name) VALUES ('admin');
+
+INSERT INTO game_modes (name, min_players, max_players, team_count, ranked) VALUES ('1v1', 2, 2, 2, TRUE);
+INSERT INTO game_modes (name, min_players, max_players, team_count, ranked) VALUES ('3v3', 6, 6, 2, TRUE);
+INSERT INTO game_modes (name, min_players, max_players, team_count, ranked) VALUES ('free-for-all', 2, 8, 1, FALSE);
 
-INSERT INTO ranks (name, image_url) VALUES ('Bronze', 'https://via.placeholder.com/150');
-INSERT INTO ranks (name, image_url) VALUES ('Silver', ' https://via.placeholder.com/150');
-INSERT INTO ranks (name, image_url) VALUES ('Gold', 'https://via.placeholder.com/150');
-INSERT INTO ranks (name, image_url) VALUES ('Platinum', 'https://via.placeholder.com/150');
-INSERT INTO ranks (name, image_url) VALUES ('Diamond', 'https://via.placeholder.com/150');
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Bronze', 'https://via.placeholder.com/150', 1, 0, 3, TRUE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Bronze', 'https://via.placeholder.com/150', 2, 350, 2, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Bronze', 'https://via.placeholder.com/150', 3, 700, 1, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Silver', ' https://via.placeholder.com/150', 4, 1000, 3, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Silver', ' https://via.placeholder.com/150', 5, 1085, 2, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Silver', ' https://via.placeholder.com/150', 6, 1170, 1, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Gold', 'https://via.placeholder.com/150', 7, 1250, 3, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Gold', 'https://via.placeholder.com/150', 8, 1335, 2, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Gold', 'https://via.placeholder.com/150', 9, 1420, 1, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Platinum', 'https://via.placeholder.com/150', 10, 1500, 3, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Platinum', 'https://via.placeholder.com/150', 11, 1585, 2, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Platinum', 'https://via.placeholder.com/150', 12, 1670, 1, FALSE);
+INSERT INTO ranks (name, image_url, tier, min_rating, division, is_default) VALUES ('Diamond', 'https://via.placeholder.com/150', 13, 1750, NULL, FALSE);
 
-INSERT INTO users (username, email, password, salt, role_id, rank_id)
-VALUES ('server', 'server@uqac.ca', '$2y$12$zcm/bCwARboBGYvyTm.89u9G2qhqZL4Bm3ZKMkCI5G59P1/hS1geC', 'd5ea01744c824dda8321ac7456803eff', (SELECT id FROM roles WHERE name = 'server'),(SELECT id FROM ranks WHERE name = 'Bronze'));
+INSERT INTO users (username, email, password, role_id, rank_id, email_verified)
+VALUES ('server', 'server@uqac.ca', '$2y$12$zcm/bCwARboBGYvyTm.89u9G2qhqZL4Bm3ZKMkCI5G59P1/hS1geC', (SELECT id FROM roles WHERE name = 'server'),(SELECT id FROM ranks WHERE is_default), TRUE);
 
 INSERT INTO achievements (name, description, image_url) VALUES ('Ten Kills', 'You killed 10 times in a game', 'https://via.placeholder.com/150');
 INSERT INTO achievements (name, description, image_url) VALUES ('First Death', 'Your first death', 'https://via.placeholder.com/150');
\ No newline at end of file